@@ -0,0 +1,50 @@
+#[macro_use]
+extern crate criterion;
+extern crate mmd;
+
+use criterion::Criterion;
+use mmd::io::pmx::view::PmxView;
+use mmd::io::pmx::PmxFile;
+
+const MODEL: &str = "asset/ニコニ立体ちゃん/Alicia_solid.pmx";
+
+fn read_model() -> Vec<u8> {
+    std::fs::read(MODEL).expect("the model is in git LFS; run `git lfs pull`")
+}
+
+/// Both read every position, weight and face index
+fn vertices_and_faces(c: &mut Criterion) {
+    let bytes = read_model();
+    c.bench_function("PmxFile::from_bytes", move |b| {
+        b.iter(|| {
+            let pmx = PmxFile::from_bytes(&bytes).unwrap();
+            let sum = pmx.model.vertices.0.iter().map(|v| v.position.0.x).sum::<f32>();
+            (sum, pmx.model.face_indices.0.len())
+        })
+    });
+    let bytes = read_model();
+    c.bench_function("PmxView::new", move |b| {
+        b.iter(|| {
+            let view = PmxView::new(&bytes).unwrap();
+            let sum = view.vertices().map(|v| v.position().x + v.weights().weights[0]).sum::<f32>();
+            (sum, view.faces().max())
+        })
+    });
+}
+
+/// Loading is dominated by the vertices and faces of this model
+fn load_file(c: &mut Criterion) {
+    c.bench_function("PmxFile::from_file", |b| b.iter(|| PmxFile::from_file(MODEL).unwrap()));
+}
+
+#[cfg(feature = "parallel")]
+fn load_parallel(c: &mut Criterion) {
+    let bytes = read_model();
+    c.bench_function("PmxFile::from_bytes_parallel", move |b| b.iter(|| PmxFile::from_bytes_parallel(&bytes).unwrap()));
+}
+
+#[cfg(not(feature = "parallel"))]
+criterion_group!(benches, vertices_and_faces, load_file);
+#[cfg(feature = "parallel")]
+criterion_group!(benches, vertices_and_faces, load_file, load_parallel);
+criterion_main!(benches);
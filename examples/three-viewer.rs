@@ -32,21 +32,14 @@ impl PmxModel {
             mint::Point2 { x: v2.x, y: 1.0 - v2.y }
         }).collect::<Vec<_>>();
 
-        let faces = f.model.face_indices.0.iter().map(|x| {
-            x.0 as u32
-        }).collect::<Vec<u32>>().chunks(3).map(|x| [x[0], x[1], x[2]]).collect::<Vec<_>>();
-
-        let num_meshes = f.model.materials.0.len();
+        let submeshes = f.model.submeshes().unwrap();
+        let num_meshes = submeshes.len();
         let mut materials = Vec::with_capacity(num_meshes);
         let mut geometries = Vec::with_capacity(num_meshes);
         let mut meshes = Vec::with_capacity(num_meshes);
-        let mut face_start: usize = 0;
-        for i in 0..num_meshes {
-            let texture_id = f.model.materials.0[i].texture_id.0;
-            let num_faces = f.model.materials.0[i].num_vertex_indices;
-            assert!(num_faces>= 0);
-            println!("{:?}: {} faces", f.model.materials.0[i].name.jp, num_faces);
-            let face_end = face_start + (num_faces as usize / 3);
+        for (m, faces) in submeshes {
+            let texture_id = m.texture_id.0;
+            println!("{:?}: {} faces", m.name.jp, faces.len());
 
             //assert!(texture_id != -1);
             let texture = if texture_id == -1 {
@@ -64,7 +57,7 @@ impl PmxModel {
             );
 
             let geometry = three::Geometry {
-                faces: faces[face_start..face_end].to_vec(),
+                faces: faces.chunks(3).map(|x| [x[0], x[1], x[2]]).collect(),
                 tex_coords: tex_coords.clone(),
                 base: three::Shape {
                     vertices: vertices.clone(),
@@ -79,8 +72,6 @@ impl PmxModel {
             let mesh = win.factory.mesh(geometry, material);
             win.scene.add(&mesh);
             meshes.push(mesh);
-
-            face_start = face_end;
         }
 
         PmxModel { f, meshes, geometries, materials }
@@ -106,9 +97,7 @@ fn main() {
             let v3 = x.position.0;
             mint::Point3 { x: v3.x, y: v3.y, z: v3.z }
         }).collect::<Vec<_>>();
-        let faces = f.model.face_indices.0.iter().map(|x| {
-            x.0 as u32
-        }).collect::<Vec<u32>>().chunks(3).map(|x| [x[0], x[1], x[2]]).collect::<Vec<_>>();
+        let faces = f.model.face_indices.0.chunks(3).map(|x| [x[0], x[1], x[2]]).collect::<Vec<_>>();
         three::Geometry {
             faces,
             base: three::Shape {
@@ -0,0 +1,170 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::result;
+
+pub type Result<T> = result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum ErrorKind {
+    /// Includes unexpected EOF in the middle of a section
+    Io(io::Error),
+    BadMagic,
+    UnknownEncoding(u8),
+    UnknownIndexSize(u8),
+    InvalidBoneWeightType(u8),
+    InvalidFrameElementType(u8),
+    InvalidBool(u8),
+    /// A value out of the range of an enum
+    InvalidValue { ty: &'static str, value: i64 },
+    InvalidString,
+    /// An index which does not fit in the configured index size
+    IndexOutOfRange(i32),
+    /// A string which does not fit in a fixed-length field
+    NameTooLong { name: String, len: usize },
+    /// A string which cannot be represented in Shift-JIS
+    UnencodableName(String),
+    SoftBodyRequiresPmx21,
+    /// A warning when loading in strict mode.
+    /// Warnings found after decoding are reported at the end of the input.
+    Strict(Warning),
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ErrorKind::*;
+        match *self {
+            Io(ref e) => write!(f, "{}", e),
+            BadMagic => write!(f, "Unknown Format"),
+            UnknownEncoding(x) => write!(f, "Unknown encoding {}", x),
+            UnknownIndexSize(x) => write!(f, "Unknown index size {}", x),
+            InvalidBoneWeightType(x) => write!(f, "Invalid BoneWeight Type {}", x),
+            InvalidFrameElementType(x) => write!(f, "Invalid FrameElement Type {}", x),
+            InvalidBool(x) => write!(f, "Invalid bool value {}", x),
+            InvalidValue { ty, value } => write!(f, "Invalid {} {}", ty, value),
+            InvalidString => write!(f, "Invalid string"),
+            IndexOutOfRange(x) => write!(f, "Index {} is out of range", x),
+            NameTooLong { ref name, len } => write!(f, "{:?} does not fit in {} bytes", name, len),
+            UnencodableName(ref name) => write!(f, "{:?} cannot be encoded in Shift-JIS", name),
+            SoftBodyRequiresPmx21 => write!(f, "Soft bodies require PMX 2.1"),
+            Strict(ref w) => write!(f, "{}", w),
+        }
+    }
+}
+
+impl error::Error for ErrorKind {}
+
+/// An error with the section and the byte offset where it happened.
+/// The offset counts from the beginning of the file.
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    section: Option<&'static str>,
+    offset: u64,
+}
+
+impl Error {
+    pub(crate) fn new(kind: ErrorKind, section: &'static str, offset: u64) -> Error {
+        Error { kind, section: Some(section), offset }
+    }
+
+    /// Unwraps the `ErrorKind` carried by `io::Error`s from the decoders.
+    pub(crate) fn from_io(e: io::Error, section: &'static str, offset: u64) -> Error {
+        let kind = if e.get_ref().map_or(false, |inner| inner.is::<ErrorKind>()) {
+            *e.into_inner().unwrap().downcast::<ErrorKind>().unwrap()
+        } else {
+            ErrorKind::Io(e)
+        };
+        Error::new(kind, section, offset)
+    }
+
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    pub fn into_kind(self) -> ErrorKind {
+        self.kind
+    }
+
+    /// `None` if the file could not be opened
+    pub fn section(&self) -> Option<&'static str> {
+        self.section
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error { kind: ErrorKind::Io(e), section: None, offset: 0 }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.section {
+            Some(section) => write!(f, "{} (in {} at byte {})", self.kind, section, self.offset),
+            None => write!(f, "{}", self.kind),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self.kind {
+            ErrorKind::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// A problem which does not stop loading
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub section: &'static str,
+    /// The element in `section`, if known
+    pub index: Option<usize>,
+    pub kind: WarningKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WarningKind {
+    /// A reference to an element which does not exist in `target`.
+    /// -1, which means none, is not reported. Face indices are `u32`,
+    /// so one above `i32::MAX` is given as a negative `index`.
+    IndexOutOfRange { target: &'static str, index: i32 },
+    /// BDEF4 or QDEF weights which do not add up to 1
+    WeightSum(f32),
+    /// Bits which are not defined in a flag set.
+    /// They are kept in `ModeSet.1`, and saving writes them back.
+    UnknownFlags { ty: &'static str, bits: u32 },
+    /// A string which was not valid UTF-16 or UTF-8,
+    /// decoded with U+FFFD in place of the invalid parts
+    InvalidString(String),
+    /// The error which stopped the lenient loader
+    Truncated(String),
+}
+
+impl fmt::Display for WarningKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::WarningKind::*;
+        match *self {
+            IndexOutOfRange { target, index } => write!(f, "Index {} is out of range of {}", index, target),
+            WeightSum(sum) => write!(f, "Weights add up to {}", sum),
+            UnknownFlags { ty, bits } => write!(f, "Unknown {} {:#x}", ty, bits),
+            InvalidString(ref s) => write!(f, "Invalid string {:?}", s),
+            Truncated(ref e) => write!(f, "Truncated: {}", e),
+        }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.index {
+            Some(index) => write!(f, "{} (in {} #{})", self.kind, self.section, index),
+            None => write!(f, "{} (in {})", self.kind, self.section),
+        }
+    }
+}
@@ -1,36 +1,269 @@
 #[macro_use]
 mod newtypes;
 
+pub mod pmd;
 pub mod pmx;
+pub mod vmd;
 
-use self::pmx::PmxFile;
+pub use self::newtypes::{Array, ModeSet, Vec2, Vec3, Vec4};
+
+use self::pmd::{PmdFile, PmdProbe};
+use self::pmx::{LoadMode, PmxFile, PmxProbe};
+use self::vmd::{VmdFile, VmdProbe};
 
 use std::path::Path;
-use std::io::{Read, Result};
+use std::io::{self, BufRead, BufReader, Read, Seek, Write};
 use std::marker::Sized;
 
-trait Load {
+use byteorder::ReadBytesExt;
+use error::{Error, ErrorKind, Result, Warning};
+
+/// `pod_io::Decode` only returns `io::Error`, so the kind is carried inside
+/// and unwrapped by `Tracker::section`.
+fn err(kind: ErrorKind) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, kind)
+}
+
+fn read_bool<R: Read>(r: &mut R) -> io::Result<bool> {
+    match r.read_u8()? {
+        0 => Ok(false),
+        1 => Ok(true),
+        x => Err(err(ErrorKind::InvalidBool(x))),
+    }
+}
+
+/// Optional sections are appended by later versions of the tools,
+/// so a file may end before any of them.
+fn is_eof<R: BufRead>(r: &mut R) -> io::Result<bool> {
+    Ok(r.fill_buf()?.is_empty())
+}
+
+/// Counts the bytes read or written, to locate errors.
+struct Tracker<T> {
+    inner: T,
+    offset: u64,
+}
+
+impl<T> Tracker<T> {
+    fn new(inner: T) -> Tracker<T> {
+        Tracker { inner, offset: 0 }
+    }
+
+    fn section<U, F>(&mut self, section: &'static str, f: F) -> Result<U>
+    where
+        F: FnOnce(&mut Self) -> io::Result<U>,
+    {
+        f(self).map_err(|e| Error::from_io(e, section, self.offset))
+    }
+
+    fn fail(&self, section: &'static str, kind: ErrorKind) -> Error {
+        Error::new(kind, section, self.offset)
+    }
+}
+
+impl<R: Read + Seek> Tracker<BufReader<R>> {
+    /// Seeks over `n` bytes, within the buffer if possible.
+    /// The last one is read, since seeking past the end is not an error.
+    fn skip(&mut self, n: u64) -> io::Result<()> {
+        if n > 0 {
+            self.inner.seek_relative(n as i64 - 1)?;
+            self.offset += n - 1;
+            self.read_exact(&mut [0])?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Tracker<&'a [u8]> {
+    /// Borrows the next `n` bytes instead of copying them
+    fn borrow(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.inner.len() < n {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer"));
+        }
+        let (head, tail) = self.inner.split_at(n);
+        self.inner = tail;
+        self.offset += n as u64;
+        Ok(head)
+    }
+}
+
+impl<R: Read> Read for Tracker<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for Tracker<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.offset += amt as u64;
+        self.inner.consume(amt)
+    }
+}
+
+impl<W: Write> Write for Tracker<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A file format which can be decoded from any reader.
+/// Implement it to get `load_bytes` and `load_file` for another format.
+pub trait Load {
+    /// `rdr` may be unbuffered, as `load_file` passes the `File` itself,
+    /// so implementations should wrap it in a `BufReader` if they make small
+    /// reads. It is then left at an unspecified position, possibly past the
+    /// end of the file.
     fn load<R: Read>(rdr: &mut R) -> Result<Self>
     where
         Self: Sized;
+
+    fn load_bytes(bytes: &[u8]) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Self::load(&mut &bytes[..])
+    }
+
+    /// Opens the file unbuffered, see `load`
+    fn load_file<P: AsRef<Path>>(path: P) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Self::load(&mut open(path)?)
+    }
+}
+
+trait Save {
+    fn save<W: Write>(&self, w: &mut W) -> Result<()>;
+}
+
+/// Unbuffered, as the loaders buffer their reader themselves
+fn open<P: AsRef<Path>>(path: P) -> Result<::std::fs::File> {
+    Ok(::std::fs::File::open(path)?)
 }
 
-trait FromFile {
-    fn _from_file<P: AsRef<Path>>(path: P) -> Result<Self>
+trait ToFile {
+    fn _to_file<P: AsRef<Path>>(&self, path: P) -> Result<()>
     where
-        Self: Sized + Load,
+        Self: Save,
     {
         use std::fs::File;
-        use std::io::BufReader;
-        let f = File::open(path)?;
-        let mut rdr = BufReader::new(f);
-        Ok(Self::load(&mut rdr)?)
+        use std::io::BufWriter;
+        let f = File::create(path)?;
+        let mut wtr = BufWriter::new(f);
+        self.save(&mut wtr)?;
+        Ok(wtr.flush()?)
     }
 }
 
-impl FromFile for PmxFile {}
+impl ToFile for PmxFile {}
 impl PmxFile {
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
-        Self::_from_file(path)
+        Self::load_file(path)
+    }
+    /// May read past the end of the file, see `Load::load`
+    pub fn from_reader<R: Read>(rdr: &mut R) -> Result<Self> {
+        Self::load(rdr)
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Self::load_bytes(bytes)
+    }
+    /// `from_bytes` with the vertices, faces and morphs decoded in parallel
+    #[cfg(feature = "parallel")]
+    pub fn from_bytes_parallel(bytes: &[u8]) -> Result<Self> {
+        Self::load_parallel(bytes)
+    }
+    /// Reads the header, the names and the section counts up to the morphs,
+    /// seeking over the rest
+    pub fn probe<R: Read + Seek>(rdr: &mut R) -> Result<PmxProbe> {
+        PmxProbe::probe(rdr)
+    }
+    pub fn probe_file<P: AsRef<Path>>(path: P) -> Result<PmxProbe> {
+        PmxProbe::probe(&mut ::std::fs::File::open(path)?)
+    }
+    /// Also returns the problems which did not stop loading
+    pub fn from_file_with_warnings<P: AsRef<Path>>(path: P) -> Result<(Self, Vec<Warning>)> {
+        Self::load_with(&mut open(path)?, LoadMode::Normal)
+    }
+    /// Fails with `ErrorKind::Strict` on the first warning
+    pub fn from_file_strict<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::load_with(&mut open(path)?, LoadMode::Strict).map(|(pmx, _)| pmx)
+    }
+    /// Keeps the sections decoded before an error, instead of failing.
+    /// The error is reported as `WarningKind::Truncated`, and the sections
+    /// which are cut off as `missing_sections`. Errors in the header still fail.
+    pub fn from_file_lenient<P: AsRef<Path>>(path: P) -> Result<(Self, Vec<Warning>)> {
+        Self::load_with(&mut open(path)?, LoadMode::Lenient)
+    }
+    /// `from_file_with_warnings`, `from_file_strict` or `from_file_lenient` for any reader.
+    /// May read past the end of the file, see `Load::load`.
+    pub fn from_reader_with<R: Read>(rdr: &mut R, mode: LoadMode) -> Result<(Self, Vec<Warning>)> {
+        Self::load_with(rdr, mode)
+    }
+    pub fn save<W: Write>(&self, w: &mut W) -> Result<()> {
+        Save::save(self, w)
+    }
+    pub fn to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self._to_file(path)
+    }
+}
+
+impl PmdFile {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::load_file(path)
+    }
+    /// May read past the end of the file, see `Load::load`
+    pub fn from_reader<R: Read>(rdr: &mut R) -> Result<Self> {
+        Self::load(rdr)
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Self::load_bytes(bytes)
+    }
+    /// Reads the header, the names and the section counts up to the morphs,
+    /// seeking over the rest
+    pub fn probe<R: Read + Seek>(rdr: &mut R) -> Result<PmdProbe> {
+        PmdProbe::probe(rdr)
+    }
+    pub fn probe_file<P: AsRef<Path>>(path: P) -> Result<PmdProbe> {
+        PmdProbe::probe(&mut ::std::fs::File::open(path)?)
+    }
+}
+
+impl ToFile for VmdFile {}
+impl VmdFile {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::load_file(path)
+    }
+    /// May read past the end of the file, see `Load::load`
+    pub fn from_reader<R: Read>(rdr: &mut R) -> Result<Self> {
+        Self::load(rdr)
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Self::load_bytes(bytes)
+    }
+    /// Reads the model name and the keyframe counts, seeking over the keyframes
+    pub fn probe<R: Read + Seek>(rdr: &mut R) -> Result<VmdProbe> {
+        VmdProbe::probe(rdr)
+    }
+    pub fn probe_file<P: AsRef<Path>>(path: P) -> Result<VmdProbe> {
+        VmdProbe::probe(&mut ::std::fs::File::open(path)?)
+    }
+    pub fn save<W: Write>(&self, w: &mut W) -> Result<()> {
+        Save::save(self, w)
+    }
+    pub fn to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self._to_file(path)
     }
 }
@@ -1,8 +1,42 @@
 use cgmath::{Vector2, Vector3, Vector4};
-use byteorder::{ByteOrder, LE};
+use byteorder::{ByteOrder, WriteBytesExt, LE};
 use pod_io::{Decode, Nil};
 use enumflags::*;
-use std::io::{Read, Result};
+use std::io::{Read, Result, Write};
+use error::WarningKind;
+
+/// The counterpart of `pod_io::Decode`
+pub trait Encode<W: Write, P> {
+    fn encode<B: ByteOrder>(&self, w: &mut W, p: P) -> Result<()>;
+}
+
+macro_rules! impl_encode_pod {
+    ($ty:ty, $f:ident) => (
+        impl<W: Write> Encode<W, Nil> for $ty {
+            fn encode<B: ByteOrder>(&self, w: &mut W, _p: Nil) -> Result<()> {
+                w.$f::<LE>(*self)
+            }
+        }
+    )
+}
+
+impl<W: Write> Encode<W, Nil> for u8 {
+    fn encode<B: ByteOrder>(&self, w: &mut W, _p: Nil) -> Result<()> {
+        w.write_u8(*self)
+    }
+}
+
+impl<W: Write> Encode<W, Nil> for i8 {
+    fn encode<B: ByteOrder>(&self, w: &mut W, _p: Nil) -> Result<()> {
+        w.write_i8(*self)
+    }
+}
+
+impl_encode_pod!(u16, write_u16);
+impl_encode_pod!(i16, write_i16);
+impl_encode_pod!(u32, write_u32);
+impl_encode_pod!(i32, write_i32);
+impl_encode_pod!(f32, write_f32);
 
 #[derive(Debug)]
 pub struct Vec2(pub Vector2<f32>);
@@ -29,35 +63,104 @@ impl<R: Read> Decode<R, Nil> for Vec4 {
     }
 }
 
+impl<W: Write> Encode<W, Nil> for Vec2 {
+    fn encode<B: ByteOrder>(&self, w: &mut W, p: Nil) -> Result<()> {
+        let v: &[f32; 2] = self.0.as_ref();
+        v.iter().try_for_each(|x| x.encode::<LE>(w, p))
+    }
+}
+
+impl<W: Write> Encode<W, Nil> for Vec3 {
+    fn encode<B: ByteOrder>(&self, w: &mut W, p: Nil) -> Result<()> {
+        let v: &[f32; 3] = self.0.as_ref();
+        v.iter().try_for_each(|x| x.encode::<LE>(w, p))
+    }
+}
+
+impl<W: Write> Encode<W, Nil> for Vec4 {
+    fn encode<B: ByteOrder>(&self, w: &mut W, p: Nil) -> Result<()> {
+        let v: &[f32; 4] = self.0.as_ref();
+        v.iter().try_for_each(|x| x.encode::<LE>(w, p))
+    }
+}
+
 #[derive(Debug)]
 pub struct Array<T>(pub Vec<T>);
 
-impl<'a, R: Read, P, T: Decode<R, &'a P> + BigStruct> Decode<R, &'a P> for Array<T> {
-    fn decode<B: ByteOrder>(r: &mut R, p: &'a P) -> Result<Array<T>> {
+/// Counts are read from the file, so at most 1 MiB is reserved up front.
+/// Every element takes at least one byte of input, so a bogus count
+/// fails at EOF before the vector grows far beyond the input length.
+pub(super) fn prealloc<T>(n: usize) -> usize {
+    const MAX_PREALLOC: usize = 1 << 20;
+    n.min(MAX_PREALLOC / ::std::mem::size_of::<T>().max(1))
+}
+
+impl<T> Default for Array<T> {
+    fn default() -> Array<T> {
+        Array(Vec::new())
+    }
+}
+
+impl<T> Array<T> {
+    /// Appends the elements of a counted array,
+    /// and keeps the ones decoded before an error
+    pub fn decode_into<'a, R: Read, P>(&mut self, r: &mut R, p: &'a P) -> Result<()>
+    where
+        T: Decode<R, &'a P>,
+    {
         let n = u32::decode::<LE>(r, Nil)? as usize;
-        println!("{}", n);
-        let mut buf = Vec::with_capacity(n);
+        self.0.reserve(prealloc::<T>(n));
         for _ in 0..n {
-            buf.push(T::decode::<LE>(r, p)?);
+            self.0.push(T::decode::<LE>(r, p)?);
         }
-        Ok(Array(buf))
+        Ok(())
+    }
+}
+
+impl<'a, R: Read, P, T: Decode<R, &'a P> + BigStruct> Decode<R, &'a P> for Array<T> {
+    fn decode<B: ByteOrder>(r: &mut R, p: &'a P) -> Result<Array<T>> {
+        let mut a = Array::default();
+        a.decode_into(r, p)?;
+        Ok(a)
     }
 }
 
-impl<R: Read> Decode<R, usize> for Array<Vec4> {
-    fn decode<B: ByteOrder>(r: &mut R, n: usize) -> Result<Array<Vec4>> {
-        let mut buf = Vec::with_capacity(n);
+/// For arrays whose length is known in advance
+impl<R: Read, T: Decode<R, Nil>> Decode<R, usize> for Array<T> {
+    fn decode<B: ByteOrder>(r: &mut R, n: usize) -> Result<Array<T>> {
+        let mut buf = Vec::with_capacity(prealloc::<T>(n));
         for _ in 0..n {
-            buf.push(Vec4::decode::<LE>(r, Nil)?);
+            buf.push(T::decode::<LE>(r, Nil)?);
         }
         Ok(Array(buf))
     }
 }
 
+impl<'a, W: Write, P, T: Encode<W, &'a P> + BigStruct> Encode<W, &'a P> for Array<T> {
+    fn encode<B: ByteOrder>(&self, w: &mut W, p: &'a P) -> Result<()> {
+        (self.0.len() as u32).encode::<LE>(w, Nil)?;
+        self.0.iter().try_for_each(|x| x.encode::<LE>(w, p))
+    }
+}
+
 pub trait BigStruct {}
 
+/// Where decoders report the problems they can recover from
+pub trait Warn {
+    fn warn(&self, kind: WarningKind) -> Result<()>;
+}
+
+/// The flags, and the bits which no flag defines.
+/// Those are kept so that saving writes back what was loaded.
 #[derive(Debug)]
-pub struct ModeSet<T: RawBitFlags + BitFlagsFmt>(pub BitFlags<T>);
+pub struct ModeSet<T: RawBitFlags + BitFlagsFmt>(pub BitFlags<T>, pub u32);
+
+impl<T: RawBitFlags + BitFlagsFmt> ModeSet<T> {
+    /// Without unknown bits
+    pub fn new(flags: BitFlags<T>) -> ModeSet<T> {
+        ModeSet(flags, 0)
+    }
+}
 
 impl<T: RawBitFlags + BitFlagsFmt> ::std::ops::Deref for ModeSet<T> {
     type Target = BitFlags<T>;
@@ -68,11 +171,15 @@ impl<T: RawBitFlags + BitFlagsFmt> ::std::ops::Deref for ModeSet<T> {
 
 macro_rules! impl_decode_modeset {
     ($ty:ty, $repr:ty) => (
-        impl<R: Read> Decode<R, Nil> for ModeSet<$ty> {
-            fn decode<B: ByteOrder>(r: &mut R, _p: Nil) -> Result<ModeSet<$ty>> {
-                BitFlags::from_bits(<$repr>::decode::<LE>(r, Nil)?)
-                    .map(|x| ModeSet(x))
-                    .ok_or(err(concat!("Invalid ", stringify!($ty), " ModeSet")))
+        impl<'a, R: Read, P: Warn> Decode<R, &'a P> for ModeSet<$ty> {
+            fn decode<B: ByteOrder>(r: &mut R, p: &'a P) -> Result<ModeSet<$ty>> {
+                let bits = <$repr>::decode::<LE>(r, Nil)?;
+                let flags = BitFlags::<$ty>::from_bits_truncate(bits);
+                let unknown = u32::from(bits & !flags.bits());
+                if unknown != 0 {
+                    p.warn(WarningKind::UnknownFlags { ty: stringify!($ty), bits: unknown })?;
+                }
+                Ok(ModeSet(flags, unknown))
             }
         }
     )
@@ -82,7 +189,28 @@ macro_rules! impl_decode_mode {
     ($ty:ty, $repr:ty) => (
         impl<R: Read> Decode<R, Nil> for $ty {
             fn decode<B: ByteOrder>(r: &mut R, _p: Nil) -> Result<$ty> {
-                <$ty>::from_u8(<$repr>::decode::<LE>(r, Nil)?).ok_or(err(concat!("Invalid ", stringify!($ty), " Mode")))
+                let value = i64::from(<$repr>::decode::<LE>(r, Nil)?);
+                <$ty>::from_i64(value).ok_or_else(|| err(ErrorKind::InvalidValue { ty: stringify!($ty), value }))
+            }
+        }
+    )
+}
+
+macro_rules! impl_encode_modeset {
+    ($ty:ty, $repr:ty) => (
+        impl<W: Write> Encode<W, Nil> for ModeSet<$ty> {
+            fn encode<B: ByteOrder>(&self, w: &mut W, _p: Nil) -> Result<()> {
+                (self.0.bits() | self.1 as $repr).encode::<LE>(w, Nil)
+            }
+        }
+    )
+}
+
+macro_rules! impl_encode_mode {
+    ($ty:ty, $repr:ty) => (
+        impl<W: Write> Encode<W, Nil> for $ty {
+            fn encode<B: ByteOrder>(&self, w: &mut W, _p: Nil) -> Result<()> {
+                (*self as $repr).encode::<LE>(w, Nil)
             }
         }
     )
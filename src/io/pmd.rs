@@ -0,0 +1,675 @@
+// Reference:
+// http://blog.goo.ne.jp/torisu_tetosuki/e/209ad341d3ece2b1b4df24abf619d6e4
+// https://github.com/benikabocha/saba
+use super::{err, is_eof, Load, Tracker};
+use super::newtypes::*;
+use super::pmx::{PhysicsMode, RigidBodyShape};
+use error::{self, ErrorKind};
+use std::io::{BufReader, Read, Result, Seek, Write};
+
+use byteorder::{ByteOrder, LE};
+use encoding::{DecoderTrap, EncoderTrap, Encoding};
+use encoding::all::WINDOWS_31J;
+use num_traits::FromPrimitive;
+use pod_io::{Decode, Nil};
+
+fn read_strings<R: Read>(r: &mut R, n: usize, len: usize) -> Result<Array<PmdString>> {
+    let mut buf = Vec::with_capacity(n);
+    for _ in 0..n {
+        buf.push(PmdString::decode::<LE>(r, len)?);
+    }
+    Ok(Array(buf))
+}
+
+#[derive(Debug)]
+pub struct PmdFile {
+    magic: [u8; 3],
+    pub version: f32,
+    pub model_name: PmdString,
+    pub comment: PmdString,
+    pub model: Model,
+    /// The English name extension
+    pub english: Option<EnglishNames>,
+}
+
+fn read_header<R: Read>(r: &mut Tracker<R>) -> error::Result<([u8; 3], f32, PmdString, PmdString)> {
+    let magic = r.section("header", |r| <[u8; 3]>::decode::<LE>(r, Nil))?;
+    if &magic != b"Pmd" {
+        return Err(r.fail("header", ErrorKind::BadMagic));
+    }
+    r.section("header", |r| {
+        Ok((magic, f32::decode::<LE>(r, Nil)?, PmdString::decode::<LE>(r, 20)?, PmdString::decode::<LE>(r, 256)?))
+    })
+}
+
+/// What `PmdFile::probe` reads without decoding the model
+#[derive(Debug)]
+pub struct PmdProbe {
+    pub version: f32,
+    pub model_name: PmdString,
+    pub comment: PmdString,
+    pub num_vertices: usize,
+    pub num_face_indices: usize,
+    pub num_materials: usize,
+    pub num_bones: usize,
+    pub num_morphs: usize,
+}
+
+impl PmdProbe {
+    pub(super) fn probe<R: Read + Seek>(rdr: &mut R) -> error::Result<PmdProbe> {
+        let r = &mut Tracker::new(BufReader::new(rdr));
+        let (_, version, model_name, comment) = read_header(r)?;
+        let num_vertices = r.section("vertices", |r| {
+            let n = u32::decode::<LE>(r, Nil)?;
+            r.skip(u64::from(n) * 38)?;
+            Ok(n as usize)
+        })?;
+        let num_face_indices = r.section("faces", |r| {
+            let n = u32::decode::<LE>(r, Nil)?;
+            r.skip(u64::from(n) * 2)?;
+            Ok(n as usize)
+        })?;
+        let num_materials = r.section("materials", |r| {
+            let n = u32::decode::<LE>(r, Nil)?;
+            r.skip(u64::from(n) * 70)?;
+            Ok(n as usize)
+        })?;
+        let num_bones = r.section("bones", |r| {
+            let n = u16::decode::<LE>(r, Nil)?;
+            r.skip(u64::from(n) * 39)?;
+            Ok(n as usize)
+        })?;
+        r.section("iks", |r| {
+            for _ in 0..u16::decode::<LE>(r, Nil)? {
+                r.skip(4)?;
+                let chain_length = u8::decode::<LE>(r, Nil)?;
+                r.skip(6 + 2 * u64::from(chain_length))?;
+            }
+            Ok(())
+        })?;
+        let num_morphs = r.section("morphs", |r| u16::decode::<LE>(r, Nil))? as usize;
+        Ok(PmdProbe { version, model_name, comment, num_vertices, num_face_indices, num_materials, num_bones, num_morphs })
+    }
+}
+
+impl Load for PmdFile {
+    fn load<R: Read>(rdr: &mut R) -> error::Result<PmdFile> {
+        let r = &mut Tracker::new(BufReader::new(rdr));
+        let (magic, version, model_name, comment) = read_header(r)?;
+
+        let vertices = r.section("vertices", |r| {
+            let n = u32::decode::<LE>(r, Nil)? as usize;
+            Array::decode::<LE>(r, n)
+        })?;
+        let face_indices = r.section("faces", |r| {
+            let n = u32::decode::<LE>(r, Nil)? as usize;
+            Array::decode::<LE>(r, n)
+        })?;
+        let materials = r.section("materials", |r| {
+            let n = u32::decode::<LE>(r, Nil)? as usize;
+            Array::decode::<LE>(r, n)
+        })?;
+        let bones = r.section("bones", |r| {
+            let n = u16::decode::<LE>(r, Nil)? as usize;
+            Array::<Bone>::decode::<LE>(r, n)
+        })?;
+        let iks = r.section("iks", |r| {
+            let n = u16::decode::<LE>(r, Nil)? as usize;
+            Array::decode::<LE>(r, n)
+        })?;
+        let morphs = r.section("morphs", |r| {
+            let n = u16::decode::<LE>(r, Nil)? as usize;
+            Array::<Morph>::decode::<LE>(r, n)
+        })?;
+        let (morph_frame, bone_frame_names, bone_frames) = r.section("display frames", |r| {
+            let n = u8::decode::<LE>(r, Nil)? as usize;
+            let morph_frame = Array::decode::<LE>(r, n)?;
+            let n = u8::decode::<LE>(r, Nil)? as usize;
+            let bone_frame_names = read_strings(r, n, 50)?;
+            let n = u32::decode::<LE>(r, Nil)? as usize;
+            Ok((morph_frame, bone_frame_names, Array::decode::<LE>(r, n)?))
+        })?;
+
+        let english = r.section("english names", |r| {
+            if is_eof(r)? || u8::decode::<LE>(r, Nil)? != 1 {
+                return Ok(None);
+            }
+            Ok(Some(EnglishNames {
+                model_name: PmdString::decode::<LE>(r, 20)?,
+                comment: PmdString::decode::<LE>(r, 256)?,
+                bone_names: read_strings(r, bones.0.len(), 20)?,
+                // the base morph has no English name
+                morph_names: read_strings(r, morphs.0.len().saturating_sub(1), 20)?,
+                bone_frame_names: read_strings(r, bone_frame_names.0.len(), 50)?,
+            }))
+        })?;
+
+        let toon_textures = r.section("toon textures", |r| {
+            if is_eof(r)? {
+                return Ok(Array(Vec::new()));
+            }
+            read_strings(r, 10, 100)
+        })?;
+        let rigid_bodies = r.section("rigid bodies", |r| {
+            if is_eof(r)? {
+                return Ok(Array(Vec::new()));
+            }
+            let n = u32::decode::<LE>(r, Nil)? as usize;
+            Array::decode::<LE>(r, n)
+        })?;
+        let joints = r.section("joints", |r| {
+            if is_eof(r)? {
+                return Ok(Array(Vec::new()));
+            }
+            let n = u32::decode::<LE>(r, Nil)? as usize;
+            Array::decode::<LE>(r, n)
+        })?;
+
+        let model = Model {
+            vertices,
+            face_indices,
+            materials,
+            bones,
+            iks,
+            morphs,
+            morph_frame,
+            bone_frame_names,
+            bone_frames,
+            toon_textures,
+            rigid_bodies,
+            joints,
+        };
+        Ok(PmdFile { magic, version, model_name, comment, model, english })
+    }
+}
+
+/// Shift-JIS string stored in a fixed-length, null-terminated field
+#[derive(Debug, Default)]
+pub struct PmdString(pub String);
+
+impl<R: Read> Decode<R, usize> for PmdString {
+    fn decode<B: ByteOrder>(r: &mut R, len: usize) -> Result<PmdString> {
+        let mut buf = vec![0u8; len];
+        r.read_exact(&mut buf)?;
+        let end = buf.iter().position(|&c| c == 0).unwrap_or(len);
+        WINDOWS_31J.decode(&buf[..end], DecoderTrap::Replace).map(PmdString).map_err(|_| err(ErrorKind::InvalidString))
+    }
+}
+
+/// Fails instead of truncating when the encoded string is longer than `len`
+impl<W: Write> Encode<W, usize> for PmdString {
+    fn encode<B: ByteOrder>(&self, w: &mut W, len: usize) -> Result<()> {
+        let mut buf = WINDOWS_31J.encode(&self.0, EncoderTrap::Strict).map_err(|_| err(ErrorKind::UnencodableName(self.0.clone())))?;
+        if buf.len() > len {
+            return Err(err(ErrorKind::NameTooLong { name: self.0.clone(), len }));
+        }
+        buf.resize(len, 0);
+        w.write_all(&buf)
+    }
+}
+
+#[derive(Debug)]
+pub struct Model {
+    pub vertices: Array<Vertex>,
+    pub face_indices: Array<u16>,
+    pub materials: Array<Material>,
+    pub bones: Array<Bone>,
+    pub iks: Array<IK>,
+    pub morphs: Array<Morph>,
+    /// Morph indices shown in the facial expression frame
+    pub morph_frame: Array<u16>,
+    pub bone_frame_names: Array<PmdString>,
+    pub bone_frames: Array<BoneFrame>,
+    /// Empty if the file ends before this section
+    pub toon_textures: Array<PmdString>,
+    /// Empty if the file ends before this section
+    pub rigid_bodies: Array<RigidBody>,
+    /// Empty if the file ends before this section
+    pub joints: Array<Joint>,
+}
+
+#[derive(Debug)]
+pub struct EnglishNames {
+    pub model_name: PmdString,
+    pub comment: PmdString,
+    pub bone_names: Array<PmdString>,
+    /// Starts from the second morph, since the base morph is not named.
+    pub morph_names: Array<PmdString>,
+    pub bone_frame_names: Array<PmdString>,
+}
+
+#[derive(Debug, Decode)]
+pub struct Vertex {
+    pub position: Vec3,
+    pub normal: Vec3,
+    pub uv: Vec2,
+    pub bone_ids: [u16; 2],
+    /// Weight of `bone_ids[0]` in percent
+    pub bone_weight: u8,
+    /// 0: draw edge, 1: no edge
+    pub edge_flag: u8,
+}
+
+#[derive(Debug, Decode)]
+pub struct Material {
+    /// RGBA
+    pub diffuse: Vec4,
+    pub intensity: f32,
+    pub specular: Vec3,
+    pub ambient: Vec3,
+    /// 0xFF means the default toon texture
+    pub toon_id: u8,
+    pub edge_flag: u8,
+    pub num_vertex_indices: u32,
+    /// "texture.bmp" or "texture.bmp*sphere.sph"
+    #[Arg = "20"]
+    pub texture: PmdString,
+}
+
+#[derive(Primitive, Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum BoneType {
+    Rotate = 0,
+    RotateTranslate = 1,
+    IK = 2,
+    Unknown = 3,
+    /// IK影響下
+    IKFollow = 4,
+    /// 回転影響下
+    RotateFollow = 5,
+    /// IK接続先
+    IKTarget = 6,
+    Invisible = 7,
+    Twist = 8,
+    /// 回転連動
+    RotateRatio = 9,
+}
+
+impl_decode_mode!(BoneType, u8);
+
+#[derive(Debug, Decode)]
+pub struct Bone {
+    #[Arg = "20"]
+    pub name: PmdString,
+    /// 0xFFFF means no parent
+    pub parent_id: u16,
+    /// 0 or 0xFFFF means no tail
+    pub tail_id: u16,
+    pub bone_type: BoneType,
+    /// The influencing bone for `IKFollow` and `RotateFollow`,
+    /// or the ratio for `RotateRatio`
+    pub ik_parent_id: u16,
+    pub position: Vec3,
+}
+
+#[derive(Debug, Decode)]
+pub struct IK {
+    pub bone_id: u16,
+    pub target_id: u16,
+    pub chain_length: u8,
+    pub iterations: u16,
+    /// Limit angle per iteration, in units of 4 radians
+    pub limit_angle: f32,
+    #[Arg = "chain_length as usize"]
+    pub chain: Array<u16>,
+}
+
+#[derive(Primitive, Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum MorphPanel {
+    Base = 0,
+    Eyebrow = 1,
+    Eye = 2,
+    Mouth = 3,
+    Other = 4,
+}
+
+impl_decode_mode!(MorphPanel, u8);
+
+/// 表情
+#[derive(Debug, Decode)]
+pub struct Morph {
+    #[Arg = "20"]
+    pub name: PmdString,
+    pub num_vertices: u32,
+    pub panel: MorphPanel,
+    #[Arg = "num_vertices as usize"]
+    pub vertices: Array<MorphVertex>,
+}
+
+#[derive(Debug, Decode)]
+pub struct MorphVertex {
+    /// An index of the model vertices for the base morph,
+    /// or an index of the base morph vertices for the others.
+    pub vertex_id: u32,
+    pub offset: Vec3,
+}
+
+#[derive(Debug, Decode)]
+pub struct BoneFrame {
+    pub bone_id: u16,
+    /// Starts from 1
+    pub frame_id: u8,
+}
+
+#[derive(Debug, Decode)]
+pub struct RigidBody {
+    #[Arg = "20"]
+    pub name: PmdString,
+    /// 0xFFFF means no related bone
+    pub bone_id: u16,
+    pub group: u8,
+    /// Bit n set means this body collides with group n
+    pub collision_mask: u16,
+    pub shape: RigidBodyShape,
+    pub size: Vec3,
+    /// Relative to the related bone
+    pub position: Vec3,
+    pub rotation: Vec3,
+    pub mass: f32,
+    pub linear_damping: f32,
+    pub angular_damping: f32,
+    pub restitution: f32,
+    pub friction: f32,
+    pub physics_mode: PhysicsMode,
+}
+
+#[derive(Debug, Decode)]
+pub struct Joint {
+    #[Arg = "20"]
+    pub name: PmdString,
+    pub rigidbody_a: u32,
+    pub rigidbody_b: u32,
+    pub position: Vec3,
+    pub rotation: Vec3,
+    pub position_min: Vec3,
+    pub position_max: Vec3,
+    pub rotation_min: Vec3,
+    pub rotation_max: Vec3,
+    pub position_spring: Vec3,
+    pub rotation_spring: Vec3,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+    use std::io::Cursor;
+
+    fn write_str(w: &mut Vec<u8>, s: &str, len: usize) {
+        let mut buf = WINDOWS_31J.encode(s, EncoderTrap::Strict).unwrap();
+        buf.resize(len, 0);
+        w.write_all(&buf).unwrap();
+    }
+
+    fn write_f32s(w: &mut Vec<u8>, fs: &[f32]) {
+        fs.iter().for_each(|&f| w.write_f32::<LE>(f).unwrap());
+    }
+
+    /// A model with one or two of everything, and every extension
+    fn full_pmd() -> Vec<u8> {
+        let mut data = Vec::new();
+        data.write_all(b"Pmd").unwrap();
+        data.write_f32::<LE>(1.0).unwrap();
+        write_str(&mut data, "初音ミク", 20);
+        write_str(&mut data, "コメント", 256);
+
+        data.write_u32::<LE>(1).unwrap();
+        write_f32s(&mut data, &[0.0, 1.0, 2.0, 0.0, 0.0, -1.0, 0.25, 0.75]);
+        data.write_u16::<LE>(0).unwrap();
+        data.write_u16::<LE>(1).unwrap();
+        data.write_u8(70).unwrap();
+        data.write_u8(1).unwrap();
+
+        data.write_u32::<LE>(3).unwrap();
+        for &i in &[0, 0, 0] {
+            data.write_u16::<LE>(i).unwrap();
+        }
+
+        data.write_u32::<LE>(1).unwrap();
+        write_f32s(&mut data, &[1.0, 0.5, 0.25, 1.0, 5.0, 0.1, 0.2, 0.3, 0.4, 0.5, 0.6]);
+        data.write_u8(0xFF).unwrap();
+        data.write_u8(1).unwrap();
+        data.write_u32::<LE>(3).unwrap();
+        write_str(&mut data, "tex.bmp*sph.sph", 20);
+
+        data.write_u16::<LE>(2).unwrap();
+        write_str(&mut data, "センター", 20);
+        data.write_u16::<LE>(0xFFFF).unwrap();
+        data.write_u16::<LE>(1).unwrap();
+        data.write_u8(BoneType::RotateTranslate as u8).unwrap();
+        data.write_u16::<LE>(0).unwrap();
+        write_f32s(&mut data, &[0.0, 0.0, 0.0]);
+        write_str(&mut data, "右足ＩＫ", 20);
+        data.write_u16::<LE>(0).unwrap();
+        data.write_u16::<LE>(0).unwrap();
+        data.write_u8(BoneType::IK as u8).unwrap();
+        data.write_u16::<LE>(0).unwrap();
+        write_f32s(&mut data, &[-1.0, 1.0, 0.0]);
+
+        data.write_u16::<LE>(1).unwrap();
+        data.write_u16::<LE>(1).unwrap();
+        data.write_u16::<LE>(0).unwrap();
+        data.write_u8(1).unwrap();
+        data.write_u16::<LE>(40).unwrap();
+        data.write_f32::<LE>(0.5).unwrap();
+        data.write_u16::<LE>(0).unwrap();
+
+        data.write_u16::<LE>(2).unwrap();
+        write_str(&mut data, "base", 20);
+        data.write_u32::<LE>(1).unwrap();
+        data.write_u8(MorphPanel::Base as u8).unwrap();
+        data.write_u32::<LE>(0).unwrap();
+        write_f32s(&mut data, &[0.0, 1.0, 2.0]);
+        write_str(&mut data, "あ", 20);
+        data.write_u32::<LE>(1).unwrap();
+        data.write_u8(MorphPanel::Mouth as u8).unwrap();
+        data.write_u32::<LE>(0).unwrap();
+        write_f32s(&mut data, &[0.0, 0.1, 0.0]);
+
+        data.write_u8(1).unwrap();
+        data.write_u16::<LE>(1).unwrap();
+        data.write_u8(1).unwrap();
+        write_str(&mut data, "足", 50);
+        data.write_u32::<LE>(1).unwrap();
+        data.write_u16::<LE>(1).unwrap();
+        data.write_u8(1).unwrap();
+
+        data.write_u8(1).unwrap();
+        write_str(&mut data, "Miku", 20);
+        write_str(&mut data, "Comment", 256);
+        write_str(&mut data, "center", 20);
+        write_str(&mut data, "leg IK R", 20);
+        write_str(&mut data, "a", 20);
+        write_str(&mut data, "Legs", 50);
+
+        for i in 0..10 {
+            write_str(&mut data, &format!("toon{:02}.bmp", i + 1), 100);
+        }
+
+        data.write_u32::<LE>(1).unwrap();
+        write_str(&mut data, "頭", 20);
+        data.write_u16::<LE>(0).unwrap();
+        data.write_u8(3).unwrap();
+        data.write_u16::<LE>(0xFFF7).unwrap();
+        data.write_u8(RigidBodyShape::Capsule as u8).unwrap();
+        write_f32s(&mut data, &[1.0, 2.0, 0.0, 0.0, 10.0, 0.0, 0.0, 0.0, 0.5, 1.0, 0.5, 0.5, 0.0, 0.5]);
+        data.write_u8(PhysicsMode::DynamicWithBone as u8).unwrap();
+
+        data.write_u32::<LE>(1).unwrap();
+        write_str(&mut data, "首", 20);
+        data.write_u32::<LE>(0).unwrap();
+        data.write_u32::<LE>(1).unwrap();
+        write_f32s(&mut data, &[0.0, 9.0, 0.0]);
+        write_f32s(&mut data, &[0.0; 3]);
+        write_f32s(&mut data, &[-1.0, -1.0, -1.0]);
+        write_f32s(&mut data, &[1.0, 1.0, 1.0]);
+        write_f32s(&mut data, &[-0.5, 0.0, 0.0]);
+        write_f32s(&mut data, &[0.5, 0.0, 0.0]);
+        write_f32s(&mut data, &[0.0; 3]);
+        write_f32s(&mut data, &[100.0, 0.0, 0.0]);
+        data
+    }
+
+    /// A model with one bone and nothing else, up to the bone frame section
+    fn minimal_pmd() -> Vec<u8> {
+        let mut data = Vec::new();
+        data.write_all(b"Pmd").unwrap();
+        data.write_f32::<LE>(1.0).unwrap();
+        write_str(&mut data, "初音ミク", 20);
+        write_str(&mut data, "コメント", 256);
+        data.write_u32::<LE>(0).unwrap();
+        data.write_u32::<LE>(0).unwrap();
+        data.write_u32::<LE>(0).unwrap();
+        data.write_u16::<LE>(1).unwrap();
+        write_str(&mut data, "センター", 20);
+        data.write_u16::<LE>(0xFFFF).unwrap();
+        data.write_u16::<LE>(0).unwrap();
+        data.write_u8(BoneType::RotateTranslate as u8).unwrap();
+        data.write_u16::<LE>(0).unwrap();
+        for _ in 0..3 {
+            data.write_f32::<LE>(0.0).unwrap();
+        }
+        data.write_u16::<LE>(0).unwrap();
+        data.write_u16::<LE>(0).unwrap();
+        data.write_u8(0).unwrap();
+        data.write_u8(0).unwrap();
+        data.write_u32::<LE>(0).unwrap();
+        data
+    }
+
+    #[test]
+    fn load_every_section() {
+        let pmd = PmdFile::from_bytes(&full_pmd()).unwrap();
+        let model = &pmd.model;
+
+        let vertex = &model.vertices.0[0];
+        assert_eq!((vertex.position.0).z, 2.0);
+        assert_eq!((vertex.normal.0).z, -1.0);
+        assert_eq!((vertex.uv.0).y, 0.75);
+        assert_eq!((vertex.bone_ids, vertex.bone_weight, vertex.edge_flag), ([0, 1], 70, 1));
+        assert_eq!(model.face_indices.0, vec![0, 0, 0]);
+
+        let material = &model.materials.0[0];
+        assert_eq!((material.diffuse.0).y, 0.5);
+        assert_eq!(material.intensity, 5.0);
+        assert_eq!((material.ambient.0).z, 0.6);
+        assert_eq!((material.toon_id, material.edge_flag, material.num_vertex_indices), (0xFF, 1, 3));
+        assert_eq!(material.texture.0, "tex.bmp*sph.sph");
+
+        let bone = &model.bones.0[1];
+        assert_eq!(bone.name.0, "右足ＩＫ");
+        assert_eq!((bone.parent_id, bone.bone_type), (0, BoneType::IK));
+        assert_eq!((bone.position.0).x, -1.0);
+
+        let ik = &model.iks.0[0];
+        assert_eq!((ik.bone_id, ik.target_id, ik.chain_length, ik.iterations), (1, 0, 1, 40));
+        assert_eq!(ik.limit_angle, 0.5);
+        assert_eq!(ik.chain.0, vec![0]);
+
+        let morph = &model.morphs.0[1];
+        assert_eq!((morph.name.0.as_str(), morph.panel), ("あ", MorphPanel::Mouth));
+        assert_eq!((morph.vertices.0[0].offset.0).y, 0.1);
+        assert_eq!((model.morphs.0[0].vertices.0[0].offset.0).z, 2.0);
+        assert_eq!(model.morph_frame.0, vec![1]);
+        assert_eq!(model.bone_frame_names.0[0].0, "足");
+        assert_eq!((model.bone_frames.0[0].bone_id, model.bone_frames.0[0].frame_id), (1, 1));
+
+        let english = pmd.english.as_ref().unwrap();
+        assert_eq!(english.bone_names.0[1].0, "leg IK R");
+        assert_eq!(english.morph_names.0.len(), 1);
+        assert_eq!(english.morph_names.0[0].0, "a");
+        assert_eq!(english.bone_frame_names.0[0].0, "Legs");
+        assert_eq!(model.toon_textures.0[0].0, "toon01.bmp");
+
+        let body = &model.rigid_bodies.0[0];
+        assert_eq!(body.name.0, "頭");
+        assert_eq!((body.bone_id, body.group, body.collision_mask), (0, 3, 0xFFF7));
+        assert_eq!((body.shape, body.physics_mode), (RigidBodyShape::Capsule, PhysicsMode::DynamicWithBone));
+        assert_eq!((body.position.0).y, 10.0);
+        assert_eq!([body.mass, body.linear_damping, body.angular_damping, body.restitution, body.friction], [1.0, 0.5, 0.5, 0.0, 0.5]);
+
+        let joint = &model.joints.0[0];
+        assert_eq!(joint.name.0, "首");
+        assert_eq!((joint.rigidbody_a, joint.rigidbody_b), (0, 1));
+        assert_eq!((joint.position.0).y, 9.0);
+        assert_eq!((joint.position_min.0).x, -1.0);
+        assert_eq!((joint.rotation_max.0).x, 0.5);
+        assert_eq!((joint.rotation_spring.0).x, 100.0);
+    }
+
+    #[test]
+    fn load_without_extensions() {
+        let pmd = PmdFile::load(&mut Cursor::new(minimal_pmd())).unwrap();
+        assert_eq!(pmd.model_name.0, "初音ミク");
+        assert_eq!(pmd.comment.0, "コメント");
+        assert_eq!(pmd.model.bones.0[0].name.0, "センター");
+        assert_eq!(pmd.model.bones.0[0].bone_type, BoneType::RotateTranslate);
+        assert!(pmd.english.is_none());
+        assert!(pmd.model.toon_textures.0.is_empty());
+        assert!(pmd.model.rigid_bodies.0.is_empty());
+    }
+
+    #[test]
+    fn load_with_extensions() {
+        let mut data = minimal_pmd();
+        data.write_u8(1).unwrap();
+        write_str(&mut data, "Miku", 20);
+        write_str(&mut data, "Comment", 256);
+        write_str(&mut data, "center", 20);
+        for i in 0..10 {
+            write_str(&mut data, &format!("toon{:02}.bmp", i + 1), 100);
+        }
+        data.write_u32::<LE>(0).unwrap();
+        data.write_u32::<LE>(0).unwrap();
+
+        let pmd = PmdFile::load(&mut Cursor::new(data)).unwrap();
+        let english = pmd.english.unwrap();
+        assert_eq!(english.model_name.0, "Miku");
+        assert_eq!(english.bone_names.0[0].0, "center");
+        assert!(english.morph_names.0.is_empty());
+        assert_eq!(pmd.model.toon_textures.0[9].0, "toon10.bmp");
+    }
+
+    #[test]
+    fn load_from_bytes_and_reader() {
+        let data = minimal_pmd();
+        let from_bytes = PmdFile::from_bytes(&data).unwrap();
+        let from_reader = PmdFile::from_reader(&mut Cursor::new(&data)).unwrap();
+        for pmd in &[from_bytes, from_reader] {
+            assert_eq!(pmd.model_name.0, "初音ミク");
+            assert_eq!(pmd.model.bones.0[0].name.0, "センター");
+        }
+        assert!(PmdFile::from_bytes(&data[..100]).is_err());
+    }
+
+    #[test]
+    fn probe_counts() {
+        let mut data = Vec::new();
+        data.write_all(b"Pmd").unwrap();
+        data.write_f32::<LE>(1.0).unwrap();
+        write_str(&mut data, "初音ミク", 20);
+        write_str(&mut data, "コメント", 256);
+        data.write_u32::<LE>(2).unwrap();
+        data.extend_from_slice(&[0; 2 * 38]);
+        data.write_u32::<LE>(3).unwrap();
+        data.extend_from_slice(&[0; 3 * 2]);
+        data.write_u32::<LE>(1).unwrap();
+        data.extend_from_slice(&[0; 70]);
+        data.write_u16::<LE>(2).unwrap();
+        data.extend_from_slice(&[0; 2 * 39]);
+        data.write_u16::<LE>(1).unwrap();
+        data.extend_from_slice(&[0; 4]);
+        data.write_u8(2).unwrap();
+        data.extend_from_slice(&[0; 6 + 2 * 2]);
+        data.write_u16::<LE>(5).unwrap();
+
+        let probe = PmdFile::probe(&mut Cursor::new(&data)).unwrap();
+        assert_eq!(probe.model_name.0, "初音ミク");
+        assert_eq!(
+            [probe.num_vertices, probe.num_face_indices, probe.num_materials, probe.num_bones, probe.num_morphs],
+            [2, 3, 1, 2, 5]
+        );
+        assert!(PmdFile::probe(&mut Cursor::new(&data[..data.len() - 1])).is_err());
+    }
+}
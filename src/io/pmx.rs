@@ -1,28 +1,63 @@
-use super::Load;
+use super::{err, read_bool, Load, Save, Tracker};
 use super::newtypes::*;
-use std::io::{Error, Read, Result};
+use error::{self, Error, ErrorKind, Warning, WarningKind};
+use std::cell::{Cell, RefCell};
+use std::io::{self, BufRead, BufReader, Read, Result, Seek, Write};
 
-use byteorder::{ByteOrder, ReadBytesExt, LE};
+use byteorder::{ByteOrder, WriteBytesExt, LE};
 use enumflags::BitFlags;
 use num_traits::{Bounded, FromPrimitive};
 use pod_io::{Decode, Nil};
 
-fn err<T: AsRef<str>>(s: T) -> Error {
-    use std::io::ErrorKind;
-    Error::new(ErrorKind::Other, s.as_ref())
+mod bulk;
+pub mod mesh;
+#[cfg(feature = "parallel")]
+mod parallel;
+pub mod pose;
+pub mod skeleton;
+pub mod validate;
+pub mod view;
+
+/// Reads `n` bytes without trusting `n` for the allocation,
+/// so a corrupt length fails at EOF instead of running out of memory.
+fn read_bytes<R: Read>(r: &mut R, n: usize) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    r.take(n as u64).read_to_end(&mut buf)?;
+    if buf.len() < n {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer"));
+    }
+    Ok(buf)
+}
+
+/// Collects warnings while loading, or fails on the first one in strict mode
+struct Diagnostics {
+    strict: bool,
+    section: Cell<&'static str>,
+    warnings: RefCell<Vec<Warning>>,
 }
 
-fn create_vec<T>(n: usize) -> Vec<T> {
-    let mut v = Vec::with_capacity(n);
-    unsafe {
-        v.set_len(n);
+impl Diagnostics {
+    fn new(strict: bool) -> Diagnostics {
+        Diagnostics { strict, section: Cell::new("header"), warnings: RefCell::new(Vec::new()) }
+    }
+
+    fn warn(&self, index: Option<usize>, kind: WarningKind) -> Result<()> {
+        let warning = Warning { section: self.section.get(), index, kind };
+        if self.strict {
+            return Err(err(ErrorKind::Strict(warning)));
+        }
+        self.warnings.borrow_mut().push(warning);
+        Ok(())
     }
-    v
 }
 
 struct PmxHelper<R> {
-    read_string: fn(rdr: &mut R) -> Result<String>,
+    version: f32,
+    diagnostics: Diagnostics,
+    read_string: fn(rdr: &mut R, d: &Diagnostics) -> Result<String>,
     additional: usize,
+    vertex_index_size: usize,
+    bone_index_size: usize,
     read_vertex_index: fn(rdr: &mut R) -> Result<i32>,
     read_texture_index: fn(rdr: &mut R) -> Result<i32>,
     read_material_index: fn(rdr: &mut R) -> Result<i32>,
@@ -32,17 +67,26 @@ struct PmxHelper<R> {
 }
 
 impl<R: Read> PmxHelper<R> {
-    fn read_utf16_string(r: &mut R) -> Result<String> {
-        let n = u32::decode::<LE>(r, Nil)? as usize / 2;
-        let mut buf = create_vec(n);
-        r.read_u16_into::<LE>(&mut buf)?;
-        Ok(String::from_utf16(&buf).unwrap())
+    fn read_utf16_string(r: &mut R, d: &Diagnostics) -> Result<String> {
+        let n = u32::decode::<LE>(r, Nil)? as usize;
+        let buf = read_bytes(r, n)?;
+        if n % 2 != 0 {
+            return Err(err(ErrorKind::InvalidString));
+        }
+        let buf = buf.chunks(2).map(LE::read_u16).collect::<Vec<_>>();
+        String::from_utf16(&buf).or_else(|_| {
+            let s = String::from_utf16_lossy(&buf);
+            d.warn(None, WarningKind::InvalidString(s.clone()))?;
+            Ok(s)
+        })
     }
-    fn read_utf8_string(r: &mut R) -> Result<String> {
+    fn read_utf8_string(r: &mut R, d: &Diagnostics) -> Result<String> {
         let n = u32::decode::<LE>(r, Nil)? as usize;
-        let mut buf = create_vec(n);
-        r.read_exact(&mut buf)?;
-        Ok(String::from_utf8(buf).unwrap())
+        String::from_utf8(read_bytes(r, n)?).or_else(|e| {
+            let s = String::from_utf8_lossy(e.as_bytes()).into_owned();
+            d.warn(None, WarningKind::InvalidString(s.clone()))?;
+            Ok(s)
+        })
     }
     fn read_index<T: Decode<R, Nil>>(r: &mut R) -> Result<i32>
     where
@@ -56,11 +100,18 @@ impl<R: Read> PmxHelper<R> {
             Ok(i32::from(raw))
         }
     }
-    fn from_header(h: &Header) -> Result<Self> {
+    /// Vertex indices of 1 and 2 bytes are unsigned, with no -1 for none
+    fn read_vertex_index_of<T: Decode<R, Nil>>(r: &mut R) -> Result<i32>
+    where
+        i32: ::std::convert::From<T>,
+    {
+        Ok(i32::from(T::decode::<LE>(r, Nil)?))
+    }
+    fn from_header(h: &Header, strict: bool) -> Result<Self> {
         let read_string = match h.encode {
             0 => Self::read_utf16_string,
             1 => Self::read_utf8_string,
-            _ => return Err(err("unknown encoding")),
+            x => return Err(err(ErrorKind::UnknownEncoding(x))),
         };
         macro_rules! fn_index {
             ($size:expr) => {
@@ -68,19 +119,28 @@ impl<R: Read> PmxHelper<R> {
                     1 => Self::read_index::<u8>,
                     2 => Self::read_index::<u16>,
                     4 => Self::read_index::<i32>,
-                    _ => return Err(err("unknown index size")),
+                    x => return Err(err(ErrorKind::UnknownIndexSize(x))),
                 }
             };
         }
-        let read_vertex_index = fn_index!(h.vertex_index_size);
+        let read_vertex_index = match h.vertex_index_size {
+            1 => Self::read_vertex_index_of::<u8>,
+            2 => Self::read_vertex_index_of::<u16>,
+            4 => Self::read_vertex_index_of::<i32>,
+            x => return Err(err(ErrorKind::UnknownIndexSize(x))),
+        };
         let read_texture_index = fn_index!(h.texture_index_size);
         let read_material_index = fn_index!(h.material_index_size);
         let read_bone_index = fn_index!(h.bone_index_size);
         let read_morph_index = fn_index!(h.morph_index_size);
         let read_rigidbody_index = fn_index!(h.rigidbody_index_size);
         Ok(PmxHelper::<R> {
+            version: h.version,
+            diagnostics: Diagnostics::new(strict),
             read_string,
             additional: h.additional as usize,
+            vertex_index_size: h.vertex_index_size as usize,
+            bone_index_size: h.bone_index_size as usize,
             read_vertex_index,
             read_texture_index,
             read_material_index,
@@ -91,6 +151,118 @@ impl<R: Read> PmxHelper<R> {
     }
 }
 
+impl<R> Warn for PmxHelper<R> {
+    fn warn(&self, kind: WarningKind) -> Result<()> {
+        self.diagnostics.warn(None, kind)
+    }
+}
+
+impl<R: Read> PmxHelper<Tracker<R>> {
+    /// `Tracker::section`, which also attributes warnings to the section
+    fn section<U, F>(&self, r: &mut Tracker<R>, section: &'static str, f: F) -> error::Result<U>
+    where
+        F: FnOnce(&mut Tracker<R>) -> Result<U>,
+    {
+        self.diagnostics.section.set(section);
+        r.section(section, f)
+    }
+}
+
+struct PmxEncodeHelper<W> {
+    version: f32,
+    vertex_index_size: usize,
+    write_string: fn(w: &mut W, s: &str) -> Result<()>,
+    write_vertex_index: fn(w: &mut W, i: i32) -> Result<()>,
+    write_texture_index: fn(w: &mut W, i: i32) -> Result<()>,
+    write_material_index: fn(w: &mut W, i: i32) -> Result<()>,
+    write_bone_index: fn(w: &mut W, i: i32) -> Result<()>,
+    write_morph_index: fn(w: &mut W, i: i32) -> Result<()>,
+    write_rigidbody_index: fn(w: &mut W, i: i32) -> Result<()>,
+}
+
+impl<W: Write> PmxEncodeHelper<W> {
+    fn write_utf16_string(w: &mut W, s: &str) -> Result<()> {
+        let buf = s.encode_utf16().collect::<Vec<_>>();
+        (buf.len() as u32 * 2).encode::<LE>(w, Nil)?;
+        buf.iter().try_for_each(|&c| w.write_u16::<LE>(c))
+    }
+    fn write_utf8_string(w: &mut W, s: &str) -> Result<()> {
+        (s.len() as u32).encode::<LE>(w, Nil)?;
+        w.write_all(s.as_bytes())
+    }
+    /// The inverse of `PmxHelper::read_index`
+    fn write_index<T: Encode<W, Nil>>(w: &mut W, i: i32) -> Result<()>
+    where
+        T: Bounded + FromPrimitive,
+    {
+        let raw = match T::from_i32(i) {
+            Some(raw) => raw,
+            None if i == -1 => T::max_value(),
+            None => return Err(err(ErrorKind::IndexOutOfRange(i))),
+        };
+        raw.encode::<LE>(w, Nil)
+    }
+    /// The inverse of `PmxHelper::read_vertex_index_of`
+    fn write_vertex_index_of<T: Encode<W, Nil>>(w: &mut W, i: i32) -> Result<()>
+    where
+        T: FromPrimitive,
+    {
+        match T::from_i32(i) {
+            Some(raw) => raw.encode::<LE>(w, Nil),
+            None => Err(err(ErrorKind::IndexOutOfRange(i))),
+        }
+    }
+    fn from_header(h: &Header) -> Result<Self> {
+        let write_string = match h.encode {
+            0 => Self::write_utf16_string,
+            1 => Self::write_utf8_string,
+            x => return Err(err(ErrorKind::UnknownEncoding(x))),
+        };
+        macro_rules! fn_index {
+            ($size:expr) => {
+                match $size {
+                    1 => Self::write_index::<u8>,
+                    2 => Self::write_index::<u16>,
+                    4 => Self::write_index::<i32>,
+                    x => return Err(err(ErrorKind::UnknownIndexSize(x))),
+                }
+            };
+        }
+        Ok(PmxEncodeHelper::<W> {
+            version: h.version,
+            vertex_index_size: h.vertex_index_size as usize,
+            write_string,
+            write_vertex_index: match h.vertex_index_size {
+                1 => Self::write_vertex_index_of::<u8>,
+                2 => Self::write_vertex_index_of::<u16>,
+                4 => Self::write_vertex_index_of::<i32>,
+                x => return Err(err(ErrorKind::UnknownIndexSize(x))),
+            },
+            write_texture_index: fn_index!(h.texture_index_size),
+            write_material_index: fn_index!(h.material_index_size),
+            write_bone_index: fn_index!(h.bone_index_size),
+            write_morph_index: fn_index!(h.morph_index_size),
+            write_rigidbody_index: fn_index!(h.rigidbody_index_size),
+        })
+    }
+}
+
+/// The sections after the header, in file order
+const SECTIONS: [&str; 12] = [
+    "model name",
+    "comment",
+    "vertices",
+    "faces",
+    "textures",
+    "materials",
+    "bones",
+    "morphs",
+    "display frames",
+    "rigid bodies",
+    "joints",
+    "soft bodies",
+];
+
 #[derive(Debug)]
 pub struct PmxFile {
     magic: [u8; 4],
@@ -98,23 +270,264 @@ pub struct PmxFile {
     pub model_name: Name,
     pub comment: Name,
     pub model: Model,
+    missing: Vec<&'static str>,
 }
 
 impl Load for PmxFile {
-    fn load<R: Read>(rdr: &mut R) -> Result<PmxFile> {
-        let magic = <[u8; 4]>::decode::<LE>(rdr, Nil)?;
-        if &magic != b"PMX " {
-            return Err(err("Unknown Format"));
+    fn load<R: Read>(rdr: &mut R) -> error::Result<PmxFile> {
+        PmxFile::load_with(rdr, LoadMode::Normal).map(|(pmx, _)| pmx)
+    }
+}
+
+/// How `PmxFile::from_reader_with` handles problems
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoadMode {
+    /// Warnings are collected
+    Normal,
+    /// The first warning is returned as `ErrorKind::Strict`
+    Strict,
+    /// An error after the header is returned as `WarningKind::Truncated`,
+    /// with the sections decoded so far
+    Lenient,
+}
+
+impl PmxFile {
+    /// The sections which were cut off or could not be decoded by the lenient loader.
+    /// Elements decoded before the error are kept, so the first one may be partially filled.
+    pub fn missing_sections(&self) -> &[&'static str] {
+        &self.missing
+    }
+
+    pub fn version(&self) -> f32 {
+        self.header.version
+    }
+
+    pub fn encoding(&self) -> TextEncoding {
+        self.header.encoding()
+    }
+
+    /// The number of additional UVs of each vertex
+    pub fn additional(&self) -> usize {
+        self.header.additional as usize
+    }
+
+    pub fn index_sizes(&self) -> IndexSizes {
+        let h = &self.header;
+        IndexSizes {
+            vertex: h.vertex_index_size,
+            texture: h.texture_index_size,
+            material: h.material_index_size,
+            bone: h.bone_index_size,
+            morph: h.morph_index_size,
+            rigid_body: h.rigidbody_index_size,
+        }
+    }
+
+    pub(super) fn load_with<R: Read>(rdr: &mut R, mode: LoadMode) -> error::Result<(PmxFile, Vec<Warning>)> {
+        let r = &mut Tracker::new(BufReader::with_capacity(bulk::CHUNK, rdr));
+        let (magic, header, helper) = read_header(r, mode == LoadMode::Strict)?;
+        let mut pmx = PmxFile {
+            magic,
+            header,
+            model_name: Name::default(),
+            comment: Name::default(),
+            model: Model::default(),
+            missing: Vec::new(),
+        };
+        let d = &helper.diagnostics;
+        if let Err(e) = pmx.load_sections(r, &helper) {
+            let section = match (mode, e.section()) {
+                (LoadMode::Lenient, Some(section)) => section,
+                _ => return Err(e),
+            };
+            let has_soft_bodies = helper.version >= 2.1;
+            pmx.missing = SECTIONS
+                .iter()
+                .skip_while(|&&s| s != section)
+                .filter(|&&s| s != "soft bodies" || has_soft_bodies)
+                .cloned()
+                .collect();
+            d.warn(None, WarningKind::Truncated(e.to_string()))
+                .map_err(|e| Error::from_io(e, section, r.offset))?;
         }
-        let header = Header::decode::<LE>(rdr, Nil)?;
-        println!("{:?}", header);
-        let helper = PmxHelper::from_header(&header)?;
-        let model_name = Name::decode::<LE>(rdr, &helper)?;
-        println!("{:?}", model_name);
-        let comment = Name::decode::<LE>(rdr, &helper)?;
-        println!("{:?}", comment);
-        let model = Model::decode::<LE>(rdr, &helper)?;
-        Ok(PmxFile { magic, header, model_name, comment, model })
+        pmx.model
+            .check(d, &pmx.missing)
+            .map_err(|e| Error::from_io(e, d.section.get(), r.offset))?;
+        Ok((pmx, d.warnings.replace(Vec::new())))
+    }
+
+    fn load_sections<R: BufRead>(&mut self, r: &mut Tracker<R>, p: &PmxHelper<Tracker<R>>) -> error::Result<()> {
+        self.model_name = p.section(r, "model name", |r| Name::decode::<LE>(r, p))?;
+        self.comment = p.section(r, "comment", |r| Name::decode::<LE>(r, p))?;
+        self.model.load(r, p)
+    }
+}
+
+fn read_header<R: Read>(r: &mut Tracker<R>, strict: bool) -> error::Result<([u8; 4], Header, PmxHelper<Tracker<R>>)> {
+    let magic = r.section("header", |r| <[u8; 4]>::decode::<LE>(r, Nil))?;
+    if &magic != b"PMX " {
+        return Err(r.fail("header", ErrorKind::BadMagic));
+    }
+    r.section("header", |r| {
+        let header = Header::decode::<LE>(r, Nil)?;
+        let helper = PmxHelper::from_header(&header, strict)?;
+        Ok((magic, header, helper))
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextEncoding {
+    Utf16,
+    Utf8,
+}
+
+/// The sizes in bytes of the indices in the file, 1, 2 or 4
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IndexSizes {
+    pub vertex: u8,
+    pub texture: u8,
+    pub material: u8,
+    pub bone: u8,
+    pub morph: u8,
+    pub rigid_body: u8,
+}
+
+/// What `PmxFile::probe` reads without decoding the model
+#[derive(Debug)]
+pub struct PmxProbe {
+    pub version: f32,
+    pub encoding: TextEncoding,
+    /// The number of additional UVs of each vertex
+    pub additional: usize,
+    pub model_name: Name,
+    pub comment: Name,
+    pub num_vertices: usize,
+    pub num_face_indices: usize,
+    pub num_textures: usize,
+    pub num_materials: usize,
+    pub num_bones: usize,
+    pub num_morphs: usize,
+}
+
+impl PmxProbe {
+    pub(super) fn probe<R: Read + Seek>(rdr: &mut R) -> error::Result<PmxProbe> {
+        use self::BoneFlags::*;
+        let r = &mut Tracker::new(BufReader::new(rdr));
+        let (_, h, helper) = read_header(r, false)?;
+        let model_name = helper.section(r, "model name", |r| Name::decode::<LE>(r, &helper))?;
+        let comment = helper.section(r, "comment", |r| Name::decode::<LE>(r, &helper))?;
+
+        let skip_string = |r: &mut Tracker<BufReader<&mut R>>| {
+            let n = u32::decode::<LE>(r, Nil)?;
+            r.skip(u64::from(n))
+        };
+        let texture = u64::from(h.texture_index_size);
+        let bone = u64::from(h.bone_index_size);
+
+        let num_vertices = r.section("vertices", |r| {
+            let n = u32::decode::<LE>(r, Nil)?;
+            for _ in 0..n {
+                r.skip(32 + 16 * u64::from(h.additional))?;
+                let (bones, weights) = match u8::decode::<LE>(r, Nil)? {
+                    0 => (1, 0),
+                    1 => (2, 4),
+                    2 | 4 => (4, 16),
+                    3 => (2, 4 + 36),
+                    ty => return Err(err(ErrorKind::InvalidBoneWeightType(ty))),
+                };
+                r.skip(bones * bone + weights + 4)?;
+            }
+            Ok(n as usize)
+        })?;
+        let num_face_indices = r.section("faces", |r| {
+            let n = u32::decode::<LE>(r, Nil)?;
+            r.skip(u64::from(n) * u64::from(h.vertex_index_size))?;
+            Ok(n as usize)
+        })?;
+        let num_textures = r.section("textures", |r| {
+            let n = u32::decode::<LE>(r, Nil)?;
+            (0..n).try_for_each(|_| skip_string(r))?;
+            Ok(n as usize)
+        })?;
+        let num_materials = r.section("materials", |r| {
+            let n = u32::decode::<LE>(r, Nil)?;
+            for _ in 0..n {
+                skip_string(r)?;
+                skip_string(r)?;
+                // colors, draw mode, edge, textures, sphere mode
+                r.skip(44 + 1 + 20 + 2 * texture + 1)?;
+                let toon = match ToonMode::decode::<LE>(r, Nil)? {
+                    ToonMode::Separate => texture,
+                    ToonMode::Common => 1,
+                };
+                r.skip(toon)?;
+                skip_string(r)?;
+                r.skip(4)?;
+            }
+            Ok(n as usize)
+        })?;
+        let num_bones = r.section("bones", |r| {
+            let n = u32::decode::<LE>(r, Nil)?;
+            for _ in 0..n {
+                skip_string(r)?;
+                skip_string(r)?;
+                r.skip(12 + bone + 4)?;
+                let flags = BitFlags::<BoneFlags>::from_bits_truncate(u16::decode::<LE>(r, Nil)?);
+                let mut size = if flags.contains(TargetMode) { bone } else { 12 };
+                if flags.contains(AppendRotate) || flags.contains(AppendTranslate) {
+                    size += bone + 4;
+                }
+                if flags.contains(AxesFixed) {
+                    size += 12;
+                }
+                if flags.contains(LocalAxes) {
+                    size += 24;
+                }
+                if flags.contains(DeformOuterParent) {
+                    size += 4;
+                }
+                r.skip(size)?;
+                if flags.contains(IK) {
+                    r.skip(bone + 8)?;
+                    for _ in 0..u32::decode::<LE>(r, Nil)? {
+                        r.skip(bone)?;
+                        if read_bool(r)? {
+                            r.skip(24)?;
+                        }
+                    }
+                }
+            }
+            Ok(n as usize)
+        })?;
+        let num_morphs = r.section("morphs", |r| u32::decode::<LE>(r, Nil))? as usize;
+
+        Ok(PmxProbe {
+            version: h.version,
+            encoding: h.encoding(),
+            additional: h.additional as usize,
+            model_name,
+            comment,
+            num_vertices,
+            num_face_indices,
+            num_textures,
+            num_materials,
+            num_bones,
+            num_morphs,
+        })
+    }
+}
+
+impl Save for PmxFile {
+    fn save<W: Write>(&self, w: &mut W) -> error::Result<()> {
+        let w = &mut Tracker::new(w);
+        let helper = w.section("header", |w| {
+            w.write_all(&self.magic)?;
+            self.header.encode::<LE>(w, Nil)?;
+            PmxEncodeHelper::from_header(&self.header)
+        })?;
+        w.section("model name", |w| self.model_name.encode::<LE>(w, &helper))?;
+        w.section("comment", |w| self.comment.encode::<LE>(w, &helper))?;
+        self.model.save(w, &helper)
     }
 }
 
@@ -132,24 +545,66 @@ struct Header {
     rigidbody_index_size: u8,
 }
 
-#[derive(Debug)]
+impl Header {
+    /// Only valid after `PmxHelper::from_header` accepted the header
+    fn encoding(&self) -> TextEncoding {
+        if self.encode == 0 {
+            TextEncoding::Utf16
+        } else {
+            TextEncoding::Utf8
+        }
+    }
+}
+
+impl<W: Write> Encode<W, Nil> for Header {
+    fn encode<B: ByteOrder>(&self, w: &mut W, _p: Nil) -> Result<()> {
+        self.version.encode::<LE>(w, Nil)?;
+        let globals = [
+            self.dummy,
+            self.encode,
+            self.additional,
+            self.vertex_index_size,
+            self.texture_index_size,
+            self.material_index_size,
+            self.bone_index_size,
+            self.morph_index_size,
+            self.rigidbody_index_size,
+        ];
+        w.write_all(&globals)
+    }
+}
+
+#[derive(Debug, Default)]
 pub struct PmxString(pub String);
 
-impl<'a, R: Read> Decode<R, &'a fn(r: &mut R) -> Result<String>> for PmxString {
-    fn decode<B: ByteOrder>(r: &mut R, p: &fn(r: &mut R) -> Result<String>) -> Result<PmxString> {
-        Ok(PmxString(p(r)?))
+impl<'a, R: Read> Decode<R, &'a PmxHelper<R>> for PmxString {
+    fn decode<B: ByteOrder>(r: &mut R, p: &PmxHelper<R>) -> Result<PmxString> {
+        Ok(PmxString((p.read_string)(r, &p.diagnostics)?))
     }
 }
 
-#[derive(Debug, Decode)]
+impl<'a, W: Write> Encode<W, &'a fn(w: &mut W, s: &str) -> Result<()>> for PmxString {
+    fn encode<B: ByteOrder>(&self, w: &mut W, p: &fn(w: &mut W, s: &str) -> Result<()>) -> Result<()> {
+        p(w, &self.0)
+    }
+}
+
+#[derive(Debug, Default, Decode)]
 #[Parameter = "&'a PmxHelper<R>"]
 pub struct Name {
-    #[Arg = "&p.read_string"]
+    #[Arg = "p"]
     pub jp: PmxString,
-    #[Arg = "&p.read_string"]
+    #[Arg = "p"]
     pub en: PmxString,
 }
 
+impl<'a, W: Write> Encode<W, &'a PmxEncodeHelper<W>> for Name {
+    fn encode<B: ByteOrder>(&self, w: &mut W, p: &PmxEncodeHelper<W>) -> Result<()> {
+        self.jp.encode::<LE>(w, &p.write_string)?;
+        self.en.encode::<LE>(w, &p.write_string)
+    }
+}
+
 #[derive(Debug)]
 pub struct Index(pub i32);
 
@@ -159,47 +614,258 @@ impl<'a, R: Read> Decode<R, &'a fn(r: &mut R) -> Result<i32>> for Index {
     }
 }
 
+impl<'a, W: Write> Encode<W, &'a fn(w: &mut W, i: i32) -> Result<()>> for Index {
+    fn encode<B: ByteOrder>(&self, w: &mut W, p: &fn(w: &mut W, i: i32) -> Result<()>) -> Result<()> {
+        p(w, self.0)
+    }
+}
+
 impl BigStruct for Vertex {}
 impl BigStruct for Index {}
 impl BigStruct for Texture {}
 impl BigStruct for Material {}
 impl BigStruct for Bone {}
 impl BigStruct for IKLink {}
+impl BigStruct for Morph {}
+impl BigStruct for GroupOffset {}
+impl BigStruct for PositionOffset {}
+impl BigStruct for BoneOffset {}
+impl BigStruct for UVOffset {}
+impl BigStruct for MaterialOffset {}
+impl BigStruct for FlipOffset {}
+impl BigStruct for ImpulseOffset {}
+impl BigStruct for DisplayFrame {}
+impl BigStruct for FrameElement {}
+impl BigStruct for RigidBody {}
+impl BigStruct for Joint {}
+impl BigStruct for SoftBody {}
+impl BigStruct for AnchorRigidBody {}
 
-#[derive(Debug, Decode)]
-#[Parameter = "&'a PmxHelper<R>"]
+#[derive(Debug, Default)]
 pub struct Model {
-    #[Arg = "p"]
     pub vertices: Array<Vertex>,
-    #[Arg = "&p.read_vertex_index"]
-    pub face_indices: Array<Index>,
-    #[Arg = "p"]
+    /// Three per triangle. Unlike other vertex references, they are never -1.
+    pub face_indices: Array<u32>,
     pub textures: Array<Texture>,
-    #[Arg = "p"]
     pub materials: Array<Material>,
-    #[Arg = "p"]
     pub bones: Array<Bone>,
+    pub morphs: Array<Morph>,
+    pub display_frames: Array<DisplayFrame>,
+    pub rigid_bodies: Array<RigidBody>,
+    pub joints: Array<Joint>,
+    /// Always empty before PMX 2.1
+    pub soft_bodies: Array<SoftBody>,
+}
+
+impl Model {
+    /// Keeps the elements decoded before an error
+    fn load<R: BufRead>(&mut self, r: &mut Tracker<R>, p: &PmxHelper<Tracker<R>>) -> error::Result<()> {
+        let vertices = bulk::VertexDecoder { additional: p.additional, bone_index_size: p.bone_index_size };
+        p.section(r, "vertices", |r| vertices.read_vertices(r, &mut self.vertices.0))?;
+        p.section(r, "faces", |r| bulk::read_faces(r, p.vertex_index_size, &mut self.face_indices.0))?;
+        p.section(r, "textures", |r| self.textures.decode_into(r, p))?;
+        p.section(r, "materials", |r| self.materials.decode_into(r, p))?;
+        p.section(r, "bones", |r| self.bones.decode_into(r, p))?;
+        p.section(r, "morphs", |r| self.morphs.decode_into(r, p))?;
+        p.section(r, "display frames", |r| self.display_frames.decode_into(r, p))?;
+        p.section(r, "rigid bodies", |r| self.rigid_bodies.decode_into(r, p))?;
+        p.section(r, "joints", |r| self.joints.decode_into(r, p))?;
+        if p.version >= 2.1 {
+            p.section(r, "soft bodies", |r| self.soft_bodies.decode_into(r, p))?;
+        }
+        Ok(())
+    }
+}
+
+impl Model {
+    fn save<W: Write>(&self, w: &mut Tracker<W>, p: &PmxEncodeHelper<Tracker<W>>) -> error::Result<()> {
+        w.section("vertices", |w| self.vertices.encode::<LE>(w, p))?;
+        w.section("faces", |w| bulk::write_faces(w, p.vertex_index_size, &self.face_indices.0))?;
+        w.section("textures", |w| self.textures.encode::<LE>(w, p))?;
+        w.section("materials", |w| self.materials.encode::<LE>(w, p))?;
+        w.section("bones", |w| self.bones.encode::<LE>(w, p))?;
+        w.section("morphs", |w| self.morphs.encode::<LE>(w, p))?;
+        w.section("display frames", |w| self.display_frames.encode::<LE>(w, p))?;
+        w.section("rigid bodies", |w| self.rigid_bodies.encode::<LE>(w, p))?;
+        w.section("joints", |w| self.joints.encode::<LE>(w, p))?;
+        if p.version >= 2.1 {
+            w.section("soft bodies", |w| self.soft_bodies.encode::<LE>(w, p))?;
+        } else if !self.soft_bodies.0.is_empty() {
+            return Err(w.fail("soft bodies", ErrorKind::SoftBodyRequiresPmx21));
+        }
+        Ok(())
+    }
+}
+
+/// -1 means none. Faces, which are unsigned, are checked separately.
+/// References to missing sections are not checked.
+fn check_index(d: &Diagnostics, missing: &[&str], i: usize, target: &'static str, index: &Index, len: usize) -> Result<()> {
+    if missing.contains(&target) {
+        return Ok(());
+    }
+    if index.0 < -1 || index.0 >= len as i32 {
+        d.warn(Some(i), WarningKind::IndexOutOfRange { target, index: index.0 })?;
+    }
+    Ok(())
+}
+
+impl Model {
+    /// The checks which need the sections after the one being checked
+    fn check(&self, d: &Diagnostics, missing: &[&str]) -> Result<()> {
+        use self::BoneWeight::*;
+        use self::MorphOffsets::*;
+        let vertices = self.vertices.0.len();
+        let textures = self.textures.0.len();
+        let materials = self.materials.0.len();
+        let bones = self.bones.0.len();
+        let morphs = self.morphs.0.len();
+        let rigid_bodies = self.rigid_bodies.0.len();
+
+        d.section.set("vertices");
+        for (i, v) in self.vertices.0.iter().enumerate() {
+            let indices = match v.bone_weight {
+                BDEF1 { ref index } => ::std::slice::from_ref(index),
+                BDEF2 { ref indices, .. } | SDEF { ref indices, .. } => &indices[..],
+                BDEF4 { ref indices, weights } | QDEF { ref indices, weights } => {
+                    let sum = weights.iter().sum::<f32>();
+                    if (sum - 1.0).abs() > 1e-3 {
+                        d.warn(Some(i), WarningKind::WeightSum(sum))?;
+                    }
+                    &indices[..]
+                }
+            };
+            for &b in indices {
+                check_index(d, missing, i, "bones", &Index(b), bones)?;
+            }
+        }
+
+        d.section.set("faces");
+        if !missing.contains(&"vertices") {
+            for (i, &f) in self.face_indices.0.iter().enumerate() {
+                if f as usize >= vertices {
+                    d.warn(Some(i), WarningKind::IndexOutOfRange { target: "vertices", index: f as i32 })?;
+                }
+            }
+        }
+
+        d.section.set("materials");
+        for (i, m) in self.materials.0.iter().enumerate() {
+            check_index(d, missing, i, "textures", &m.texture_id, textures)?;
+            check_index(d, missing, i, "textures", &m.sphere_texture_id, textures)?;
+            if let ToonMode::Separate = m.toon_mode {
+                check_index(d, missing, i, "textures", &m.toon_texture_id, textures)?;
+            }
+        }
+
+        d.section.set("bones");
+        for (i, b) in self.bones.0.iter().enumerate() {
+            let extra = &b.extra;
+            check_index(d, missing, i, "bones", &b.parent_id, bones)?;
+            if let TailTarget::Bone(ref tail) = extra.tail {
+                check_index(d, missing, i, "bones", tail, bones)?;
+            }
+            if let Some(ref append) = extra.append {
+                check_index(d, missing, i, "bones", &append.parent, bones)?;
+            }
+            if let Some(ref ik) = extra.ik {
+                check_index(d, missing, i, "bones", &ik.target, bones)?;
+                for link in &ik.links.0 {
+                    check_index(d, missing, i, "bones", &link.bone_id, bones)?;
+                }
+            }
+        }
+
+        d.section.set("morphs");
+        for (i, m) in self.morphs.0.iter().enumerate() {
+            match m.offsets {
+                Group(ref o) => o.0.iter().try_for_each(|o| check_index(d, missing, i, "morphs", &o.morph_id, morphs))?,
+                Flip(ref o) => o.0.iter().try_for_each(|o| check_index(d, missing, i, "morphs", &o.morph_id, morphs))?,
+                Position(ref o) => o.0.iter().try_for_each(|o| check_index(d, missing, i, "vertices", &o.vertex_id, vertices))?,
+                UV(ref o) | AddUV1(ref o) | AddUV2(ref o) | AddUV3(ref o) | AddUV4(ref o) => {
+                    o.0.iter().try_for_each(|o| check_index(d, missing, i, "vertices", &o.vertex_id, vertices))?
+                }
+                Bone(ref o) => o.0.iter().try_for_each(|o| check_index(d, missing, i, "bones", &o.bone_id, bones))?,
+                Material(ref o) => o.0.iter().try_for_each(|o| check_index(d, missing, i, "materials", &o.material_id, materials))?,
+                Impulse(ref o) => o.0.iter().try_for_each(|o| check_index(d, missing, i, "rigid bodies", &o.rigidbody_id, rigid_bodies))?,
+            }
+        }
+
+        d.section.set("display frames");
+        for (i, f) in self.display_frames.0.iter().enumerate() {
+            for e in &f.elements.0 {
+                match *e {
+                    FrameElement::Bone(ref b) => check_index(d, missing, i, "bones", b, bones)?,
+                    FrameElement::Morph(ref m) => check_index(d, missing, i, "morphs", m, morphs)?,
+                }
+            }
+        }
+
+        d.section.set("rigid bodies");
+        for (i, b) in self.rigid_bodies.0.iter().enumerate() {
+            check_index(d, missing, i, "bones", &b.bone_id, bones)?;
+        }
+
+        d.section.set("joints");
+        for (i, j) in self.joints.0.iter().enumerate() {
+            check_index(d, missing, i, "rigid bodies", &j.rigidbody_a, rigid_bodies)?;
+            check_index(d, missing, i, "rigid bodies", &j.rigidbody_b, rigid_bodies)?;
+        }
+
+        d.section.set("soft bodies");
+        for (i, b) in self.soft_bodies.0.iter().enumerate() {
+            check_index(d, missing, i, "materials", &b.material_id, materials)?;
+            for a in &b.anchors.0 {
+                check_index(d, missing, i, "rigid bodies", &a.rigidbody_id, rigid_bodies)?;
+                check_index(d, missing, i, "vertices", &a.vertex_id, vertices)?;
+            }
+            for v in &b.pin_vertices.0 {
+                check_index(d, missing, i, "vertices", v, vertices)?;
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Decode)]
 #[Parameter = "&'a PmxHelper<R>"]
 pub struct Vertex {
     pub position: Vec3,
-    normal: Vec3,
+    pub normal: Vec3,
     pub uv: Vec2,
+    /// As many as `PmxFile::additional`, used by shaders and UV morphs
     #[Arg = "p.additional"]
-    additional: Array<Vec4>,
+    pub additional: Array<Vec4>,
     #[Arg = "p"]
-    bone_weight: BoneWeight,
-    edge_scale: f32,
+    pub bone_weight: BoneWeight,
+    /// Multiplies the edge size of the material
+    pub edge_scale: f32,
+}
+
+impl<'a, W: Write> Encode<W, &'a PmxEncodeHelper<W>> for Vertex {
+    fn encode<B: ByteOrder>(&self, w: &mut W, p: &PmxEncodeHelper<W>) -> Result<()> {
+        self.position.encode::<LE>(w, Nil)?;
+        self.normal.encode::<LE>(w, Nil)?;
+        self.uv.encode::<LE>(w, Nil)?;
+        for v in &self.additional.0 {
+            v.encode::<LE>(w, Nil)?;
+        }
+        self.bone_weight.encode::<LE>(w, p)?;
+        self.edge_scale.encode::<LE>(w, Nil)
+    }
 }
 
+/// How a vertex follows the bones, which are -1 if unused.
+/// The weight of BDEF2 and SDEF is the one of the first bone,
+/// and the second bone gets the rest.
 #[derive(Debug)]
-enum BoneWeight {
+pub enum BoneWeight {
     BDEF1 { index: i32 },
     BDEF2 { indices: [i32; 2], weight: f32 },
     BDEF4 { indices: [i32; 4], weights: [f32; 4] },
+    /// Spherical deform around the center `c`, with `r0` and `r1`
+    /// the control points of the two bones
     SDEF { indices: [i32; 2], weight: f32, c: Vec3, r0: Vec3, r1: Vec3 },
+    /// Dual quaternion deform, PMX 2.1
     QDEF { indices: [i32; 4], weights: [f32; 4] },
 }
 
@@ -228,19 +894,61 @@ impl<'a, R: Read> Decode<R, &'a PmxHelper<R>> for BoneWeight {
                 indices: [fi(r)?, fi(r)?, fi(r)?, fi(r)?],
                 weights: [fw(r)?, fw(r)?, fw(r)?, fw(r)?],
             },
-            _ => return Err(err(format!("Invalid BoneWeight Type {}", ty))),
+            _ => return Err(err(ErrorKind::InvalidBoneWeightType(ty))),
         };
         Ok(bone_weight)
     }
 }
 
+impl<'a, W: Write> Encode<W, &'a PmxEncodeHelper<W>> for BoneWeight {
+    fn encode<B: ByteOrder>(&self, w: &mut W, p: &PmxEncodeHelper<W>) -> Result<()> {
+        use self::BoneWeight::{BDEF1, BDEF2, BDEF4, QDEF, SDEF};
+        let fi = p.write_bone_index;
+        let fw = |w: &mut W, x: f32| x.encode::<LE>(w, Nil);
+        match *self {
+            BDEF1 { index } => {
+                w.write_u8(0)?;
+                fi(w, index)
+            }
+            BDEF2 { indices, weight } => {
+                w.write_u8(1)?;
+                indices.iter().try_for_each(|&i| fi(w, i))?;
+                fw(w, weight)
+            }
+            BDEF4 { indices, weights } => {
+                w.write_u8(2)?;
+                indices.iter().try_for_each(|&i| fi(w, i))?;
+                weights.iter().try_for_each(|&x| fw(w, x))
+            }
+            SDEF { indices, weight, ref c, ref r0, ref r1 } => {
+                w.write_u8(3)?;
+                indices.iter().try_for_each(|&i| fi(w, i))?;
+                fw(w, weight)?;
+                c.encode::<LE>(w, Nil)?;
+                r0.encode::<LE>(w, Nil)?;
+                r1.encode::<LE>(w, Nil)
+            }
+            QDEF { indices, weights } => {
+                w.write_u8(4)?;
+                indices.iter().try_for_each(|&i| fi(w, i))?;
+                weights.iter().try_for_each(|&x| fw(w, x))
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Texture(pub PmxString);
 
 impl<'a, R: Read> Decode<R, &'a PmxHelper<R>> for Texture {
     fn decode<B: ByteOrder>(r: &mut R, p: &PmxHelper<R>) -> Result<Texture> {
-        let s = (p.read_string)(r)?;
-        Ok(Texture(PmxString(s)))
+        Ok(Texture(PmxString::decode::<LE>(r, p)?))
+    }
+}
+
+impl<'a, W: Write> Encode<W, &'a PmxEncodeHelper<W>> for Texture {
+    fn encode<B: ByteOrder>(&self, w: &mut W, p: &PmxEncodeHelper<W>) -> Result<()> {
+        (self.0).encode::<LE>(w, &p.write_string)
     }
 }
 
@@ -258,52 +966,122 @@ pub enum DrawModeFlags {
 }
 
 impl_decode_modeset!(DrawModeFlags, u8);
+impl_encode_modeset!(DrawModeFlags, u8);
 
-#[derive(Primitive, Debug, Clone, Copy)]
+#[derive(Primitive, Debug, Clone, Copy, PartialEq)]
 #[repr(u8)]
-enum SphereMode {
-    NONE = 0,
-    MUL = 1,
-    ADD = 2,
-    SUB = 3,
+pub enum SphereMode {
+    Disabled = 0,
+    Mul = 1,
+    Add = 2,
+    /// The sphere texture is sampled with the first additional UV
+    SubTexture = 3,
 }
 
 impl_decode_mode!(SphereMode, u8);
+impl_encode_mode!(SphereMode, u8);
 
-#[derive(Primitive, Debug)]
+#[derive(Primitive, Debug, Clone, Copy, PartialEq)]
 #[repr(u8)]
-enum ToonMode {
+pub enum ToonMode {
+    /// `toon_texture_id` is an index into the textures
     Separate = 0,
+    /// `toon_texture_id` is 0 to 9, for toon01.bmp to toon10.bmp shared by MMD
     Common = 1,
 }
 
 impl_decode_mode!(ToonMode, u8);
+impl_encode_mode!(ToonMode, u8);
 
-#[derive(Debug, Decode)]
-#[Parameter = "&'a PmxHelper<R>"]
+#[derive(Debug)]
 pub struct Material {
-    #[Arg = "p"]
     pub name: Name,
-    diffuse: Vec4,
-    specular: Vec3,
-    intensity: f32,
-    ambient: Vec3,
-    draw_mode: ModeSet<DrawModeFlags>,
-    edge_color: Vec4,
-    edge_size: f32,
-    #[Arg = "&p.read_texture_index"]
+    /// RGBA
+    pub diffuse: Vec4,
+    pub specular: Vec3,
+    /// The exponent of the specular
+    pub intensity: f32,
+    pub ambient: Vec3,
+    pub draw_mode: ModeSet<DrawModeFlags>,
+    /// RGBA
+    pub edge_color: Vec4,
+    pub edge_size: f32,
     pub texture_id: Index,
-    #[Arg = "&p.read_texture_index"]
     pub sphere_texture_id: Index,
-    sphere_mode: SphereMode,
-    toon_mode: ToonMode,
-    #[Arg = "&p.read_texture_index"]
+    pub sphere_mode: SphereMode,
+    pub toon_mode: ToonMode,
+    /// Depends on `toon_mode`
     pub toon_texture_id: Index,
-    #[Arg = "&p.read_string"]
-    memo: PmxString,
+    pub memo: PmxString,
+    /// The number of face indices of this material,
+    /// which follow the ones of the materials before it
     pub num_vertex_indices: i32,
 }
 
+impl<'a, R: Read> Decode<R, &'a PmxHelper<R>> for Material {
+    fn decode<B: ByteOrder>(r: &mut R, p: &PmxHelper<R>) -> Result<Material> {
+        let name = Name::decode::<LE>(r, p)?;
+        let diffuse = Vec4::decode::<LE>(r, Nil)?;
+        let specular = Vec3::decode::<LE>(r, Nil)?;
+        let intensity = f32::decode::<LE>(r, Nil)?;
+        let ambient = Vec3::decode::<LE>(r, Nil)?;
+        let draw_mode = ModeSet::decode::<LE>(r, p)?;
+        let edge_color = Vec4::decode::<LE>(r, Nil)?;
+        let edge_size = f32::decode::<LE>(r, Nil)?;
+        let texture_id = Index::decode::<LE>(r, &p.read_texture_index)?;
+        let sphere_texture_id = Index::decode::<LE>(r, &p.read_texture_index)?;
+        let sphere_mode = SphereMode::decode::<LE>(r, Nil)?;
+        let toon_mode = ToonMode::decode::<LE>(r, Nil)?;
+        let toon_texture_id = match toon_mode {
+            ToonMode::Separate => Index::decode::<LE>(r, &p.read_texture_index)?,
+            ToonMode::Common => Index(i32::from(u8::decode::<LE>(r, Nil)?)),
+        };
+        Ok(Material {
+            name,
+            diffuse,
+            specular,
+            intensity,
+            ambient,
+            draw_mode,
+            edge_color,
+            edge_size,
+            texture_id,
+            sphere_texture_id,
+            sphere_mode,
+            toon_mode,
+            toon_texture_id,
+            memo: PmxString::decode::<LE>(r, p)?,
+            num_vertex_indices: i32::decode::<LE>(r, Nil)?,
+        })
+    }
+}
+
+impl<'a, W: Write> Encode<W, &'a PmxEncodeHelper<W>> for Material {
+    fn encode<B: ByteOrder>(&self, w: &mut W, p: &PmxEncodeHelper<W>) -> Result<()> {
+        self.name.encode::<LE>(w, p)?;
+        self.diffuse.encode::<LE>(w, Nil)?;
+        self.specular.encode::<LE>(w, Nil)?;
+        self.intensity.encode::<LE>(w, Nil)?;
+        self.ambient.encode::<LE>(w, Nil)?;
+        self.draw_mode.encode::<LE>(w, Nil)?;
+        self.edge_color.encode::<LE>(w, Nil)?;
+        self.edge_size.encode::<LE>(w, Nil)?;
+        self.texture_id.encode::<LE>(w, &p.write_texture_index)?;
+        self.sphere_texture_id.encode::<LE>(w, &p.write_texture_index)?;
+        self.sphere_mode.encode::<LE>(w, Nil)?;
+        self.toon_mode.encode::<LE>(w, Nil)?;
+        match self.toon_mode {
+            ToonMode::Separate => self.toon_texture_id.encode::<LE>(w, &p.write_texture_index)?,
+            ToonMode::Common => match u8::from_i32(self.toon_texture_id.0) {
+                Some(toon) => toon.encode::<LE>(w, Nil)?,
+                None => return Err(err(ErrorKind::IndexOutOfRange(self.toon_texture_id.0))),
+            },
+        }
+        self.memo.encode::<LE>(w, &p.write_string)?;
+        self.num_vertex_indices.encode::<LE>(w, Nil)
+    }
+}
+
 #[derive(EnumFlags, Debug, Clone, Copy)]
 #[repr(u16)]
 pub enum BoneFlags {
@@ -324,91 +1102,246 @@ pub enum BoneFlags {
 }
 
 impl_decode_modeset!(BoneFlags, u16);
+impl_encode_modeset!(BoneFlags, u16);
 
 #[derive(Debug)]
-struct IKLink {
-    bone_id: Index,
-    /// Ret: Some(min, max)
-    limits: Option<(Vec3, Vec3)>,
+pub struct IKLink {
+    pub bone_id: Index,
+    /// The minimum and maximum angles in radians, around the local axes
+    pub limits: Option<(Vec3, Vec3)>,
 }
 
 impl<'a, R: Read> Decode<R, &'a PmxHelper<R>> for IKLink {
     fn decode<B: ByteOrder>(r: &mut R, p: &PmxHelper<R>) -> Result<IKLink> {
         let bone_id = Index::decode::<LE>(r, &p.read_bone_index)?;
-        let limits = match r.read_u8()? {
-            0 => None,
-            1 => Some((Vec3::decode::<LE>(r, Nil)?, Vec3::decode::<LE>(r, Nil)?)),
-            _ => return Err(err("Invalid bool value")),
-        };
+        let limits = if read_bool(r)? { Some((Vec3::decode::<LE>(r, Nil)?, Vec3::decode::<LE>(r, Nil)?)) } else { None };
         Ok(IKLink { bone_id, limits })
     }
 }
 
+impl<'a, W: Write> Encode<W, &'a PmxEncodeHelper<W>> for IKLink {
+    fn encode<B: ByteOrder>(&self, w: &mut W, p: &PmxEncodeHelper<W>) -> Result<()> {
+        self.bone_id.encode::<LE>(w, &p.write_bone_index)?;
+        match self.limits {
+            Some((ref min, ref max)) => {
+                w.write_u8(1)?;
+                min.encode::<LE>(w, Nil)?;
+                max.encode::<LE>(w, Nil)
+            }
+            None => w.write_u8(0),
+        }
+    }
+}
+
 #[derive(Debug, Decode)]
 #[Parameter = "&'a PmxHelper<R>"]
 pub struct Bone {
-    #[Arg = "&p.read_string"]
-    name: PmxString,
-    #[Arg = "&p.read_string"]
-    name_en: PmxString,
-    position: Vec3,
+    #[Arg = "p"]
+    pub name: PmxString,
+    #[Arg = "p"]
+    pub name_en: PmxString,
+    /// In model space, not relative to the parent
+    pub position: Vec3,
+    /// -1 for a root bone
     #[Arg = "&p.read_bone_index"]
-    parent_id: Index,
-    deform_depth: i32,
-    flags: ModeSet<BoneFlags>,
+    pub parent_id: Index,
+    /// Bones are deformed in order of this, then of their index
+    pub deform_depth: i32,
+    #[Arg = "p"]
+    pub flags: ModeSet<BoneFlags>,
+    /// The fields which the flags say are present.
+    /// They take precedence over the flags when saving.
     #[Arg = "(p, &flags)"]
-    extra: BoneExtraInfo,
+    pub extra: BoneExtraInfo,
+}
+
+impl<'a, W: Write> Encode<W, &'a PmxEncodeHelper<W>> for Bone {
+    fn encode<B: ByteOrder>(&self, w: &mut W, p: &PmxEncodeHelper<W>) -> Result<()> {
+        self.name.encode::<LE>(w, &p.write_string)?;
+        self.name_en.encode::<LE>(w, &p.write_string)?;
+        self.position.encode::<LE>(w, Nil)?;
+        self.parent_id.encode::<LE>(w, &p.write_bone_index)?;
+        self.deform_depth.encode::<LE>(w, Nil)?;
+        ModeSet(self.extra.flags(self.flags.0), self.flags.1).encode::<LE>(w, Nil)?;
+        self.extra.encode::<LE>(w, p)
+    }
+}
+
+/// Where the tail of a bone is drawn
+#[derive(Debug)]
+pub enum TailTarget {
+    /// Relative to the position of the bone
+    Offset(Vec3),
+    /// The position of another bone, or nothing for -1
+    Bone(Index),
+}
+
+/// 付与: inherits a part of the rotation or translation of another bone
+#[derive(Debug)]
+pub struct AppendTransform {
+    pub parent: Index,
+    /// May be negative, to rotate the other way
+    pub ratio: f32,
+    pub rotate: bool,
+    pub translate: bool,
+    /// Inherits the local transform of the parent instead of the deform
+    pub local: bool,
+}
+
+/// The x and z axes of the bone for the UI, in model space
+#[derive(Debug)]
+pub struct LocalAxes {
+    pub x: Vec3,
+    pub z: Vec3,
+}
+
+#[derive(Debug)]
+pub struct IkSolver {
+    /// The bone which is moved to the position of the IK bone
+    pub target: Index,
+    pub iterations: i32,
+    /// The maximum rotation of a link per iteration, in radians
+    pub limit_angle: f32,
+    /// From the target towards the root
+    pub links: Array<IKLink>,
 }
 
+/// The parts of a bone which depend on its flags.
+/// When saving, the flags which tell which of them are present are set from them.
 #[derive(Debug)]
-struct BoneExtraInfo {
-    position_offset: Option<Vec3>,
-    link_id: Option<Index>,
-    /// Ret: Some(bone_id, weight)
-    append: Option<(Index, f32)>,
-    fixed_axes: Option<Vec3>,
-    /// Ret: Some(rotX, rotZ)
-    local_rot: Option<(Vec3, Vec3)>,
-    key_value: Option<i32>,
-    /// Ret: Some(bone_id, num_iterations, limit, vec![IKLink, n])
-    ik: Option<(Index, i32, f32, Array<IKLink>)>,
+pub struct BoneExtraInfo {
+    /// `BoneFlags::TargetMode`
+    pub tail: TailTarget,
+    /// `AppendRotate`, `AppendTranslate` and `AppendLocal`.
+    /// Saved as `None` if it inherits neither rotation nor translation,
+    /// in which case `AppendLocal` is saved as it is in the flags.
+    pub append: Option<AppendTransform>,
+    /// `AxesFixed`
+    pub fixed_axes: Option<Vec3>,
+    /// `BoneFlags::LocalAxes`
+    pub local_axes: Option<LocalAxes>,
+    /// `DeformOuterParent`
+    pub key_value: Option<i32>,
+    /// `IK`
+    pub ik: Option<IkSolver>,
+}
+
+impl BoneExtraInfo {
+    /// `append`, unless it inherits neither rotation nor translation,
+    /// in which case the file has no room for it
+    fn saved_append(&self) -> Option<&AppendTransform> {
+        self.append.as_ref().filter(|a| a.rotate || a.translate)
+    }
+
+    /// `flags`, with the bits for the optional fields set from them
+    fn flags(&self, mut flags: BitFlags<BoneFlags>) -> BitFlags<BoneFlags> {
+        use self::BoneFlags::*;
+        let mut set = |flag: BoneFlags, on: bool| {
+            if on {
+                flags.insert(flag)
+            } else {
+                flags.remove(flag)
+            }
+        };
+        set(TargetMode, match self.tail {
+            TailTarget::Offset(_) => false,
+            TailTarget::Bone(_) => true,
+        });
+        let append = self.saved_append();
+        // Nothing is stored for the bit alone, so a loaded one is kept
+        if let Some(append) = append {
+            set(AppendLocal, append.local);
+        }
+        set(AppendRotate, append.map_or(false, |a| a.rotate));
+        set(AppendTranslate, append.map_or(false, |a| a.translate));
+        set(AxesFixed, self.fixed_axes.is_some());
+        set(BoneFlags::LocalAxes, self.local_axes.is_some());
+        set(DeformOuterParent, self.key_value.is_some());
+        set(IK, self.ik.is_some());
+        flags
+    }
 }
 
 impl<'a, 'b, R: Read> Decode<R, (&'a PmxHelper<R>, &'b ModeSet<BoneFlags>)> for BoneExtraInfo {
     fn decode<B: ByteOrder>(r: &mut R, p: (&PmxHelper<R>, &ModeSet<BoneFlags>)) -> Result<BoneExtraInfo> {
         use self::BoneFlags::*;
         let (helper, flags) = p;
-        let (position_offset, link_id) = if flags.contains(TargetMode) {
-            (None, Some(Index::decode::<LE>(r, &helper.read_bone_index)?))
+        let read_bone_index = |r: &mut R| Index::decode::<LE>(r, &helper.read_bone_index);
+        let tail = if flags.contains(TargetMode) {
+            TailTarget::Bone(read_bone_index(r)?)
         } else {
-            (Some(Vec3::decode::<LE>(r, Nil)?), None)
+            TailTarget::Offset(Vec3::decode::<LE>(r, Nil)?)
         };
         let append = if flags.contains(AppendRotate) || flags.contains(AppendTranslate) {
-            Some((Index::decode::<LE>(r, &helper.read_bone_index)?, f32::decode::<LE>(r, Nil)?))
+            Some(AppendTransform {
+                parent: read_bone_index(r)?,
+                ratio: f32::decode::<LE>(r, Nil)?,
+                rotate: flags.contains(AppendRotate),
+                translate: flags.contains(AppendTranslate),
+                local: flags.contains(AppendLocal),
+            })
         } else {
             None
         };
         let fixed_axes = if flags.contains(AxesFixed) { Some(Vec3::decode::<LE>(r, Nil)?) } else { None };
-        let local_rot = if flags.contains(LocalAxes) { Some((Vec3::decode::<LE>(r, Nil)?, Vec3::decode::<LE>(r, Nil)?)) } else { None };
+        let local_axes = if flags.contains(BoneFlags::LocalAxes) {
+            Some(self::LocalAxes { x: Vec3::decode::<LE>(r, Nil)?, z: Vec3::decode::<LE>(r, Nil)? })
+        } else {
+            None
+        };
         let key_value = if flags.contains(DeformOuterParent) { Some(i32::decode::<LE>(r, Nil)?) } else { None };
         let ik = if flags.contains(IK) {
-            Some((Index::decode::<LE>(r, &helper.read_bone_index)?, i32::decode::<LE>(r, Nil)?, f32::decode::<LE>(r, Nil)?, Array::<IKLink>::decode::<LE>(r, helper)?))
+            Some(IkSolver {
+                target: read_bone_index(r)?,
+                iterations: i32::decode::<LE>(r, Nil)?,
+                limit_angle: f32::decode::<LE>(r, Nil)?,
+                links: Array::<IKLink>::decode::<LE>(r, helper)?,
+            })
         } else {
             None
         };
 
         Ok(BoneExtraInfo {
-            position_offset,
-            link_id,
+            tail,
             append,
             fixed_axes,
-            local_rot,
+            local_axes,
             key_value,
             ik,
         })
     }
 }
 
+impl<'a, W: Write> Encode<W, &'a PmxEncodeHelper<W>> for BoneExtraInfo {
+    fn encode<B: ByteOrder>(&self, w: &mut W, p: &PmxEncodeHelper<W>) -> Result<()> {
+        match self.tail {
+            TailTarget::Offset(ref offset) => offset.encode::<LE>(w, Nil)?,
+            TailTarget::Bone(ref bone) => bone.encode::<LE>(w, &p.write_bone_index)?,
+        }
+        if let Some(append) = self.saved_append() {
+            append.parent.encode::<LE>(w, &p.write_bone_index)?;
+            append.ratio.encode::<LE>(w, Nil)?;
+        }
+        if let Some(ref axis) = self.fixed_axes {
+            axis.encode::<LE>(w, Nil)?;
+        }
+        if let Some(ref axes) = self.local_axes {
+            axes.x.encode::<LE>(w, Nil)?;
+            axes.z.encode::<LE>(w, Nil)?;
+        }
+        if let Some(key) = self.key_value {
+            key.encode::<LE>(w, Nil)?;
+        }
+        if let Some(ref ik) = self.ik {
+            ik.target.encode::<LE>(w, &p.write_bone_index)?;
+            ik.iterations.encode::<LE>(w, Nil)?;
+            ik.limit_angle.encode::<LE>(w, Nil)?;
+            ik.links.encode::<LE>(w, p)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Primitive, Debug, Clone, Copy)]
 #[repr(u8)]
 enum MorphType {
@@ -426,3 +1359,1509 @@ enum MorphType {
 }
 
 impl_decode_mode!(MorphType, u8);
+impl_encode_mode!(MorphType, u8);
+
+#[derive(Primitive, Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum MorphPanel {
+    System = 0,
+    Eyebrow = 1,
+    Eye = 2,
+    Mouth = 3,
+    Other = 4,
+}
+
+impl_decode_mode!(MorphPanel, u8);
+impl_encode_mode!(MorphPanel, u8);
+
+#[derive(Debug, Decode)]
+#[Parameter = "&'a PmxHelper<R>"]
+pub struct Morph {
+    #[Arg = "p"]
+    pub name: Name,
+    pub panel: MorphPanel,
+    #[Arg = "p"]
+    pub offsets: MorphOffsets,
+}
+
+impl<'a, W: Write> Encode<W, &'a PmxEncodeHelper<W>> for Morph {
+    fn encode<B: ByteOrder>(&self, w: &mut W, p: &PmxEncodeHelper<W>) -> Result<()> {
+        self.name.encode::<LE>(w, p)?;
+        self.panel.encode::<LE>(w, Nil)?;
+        self.offsets.encode::<LE>(w, p)
+    }
+}
+
+/// The offset list of a morph, tagged by its morph type.
+#[derive(Debug)]
+pub enum MorphOffsets {
+    Group(Array<GroupOffset>),
+    Position(Array<PositionOffset>),
+    Bone(Array<BoneOffset>),
+    UV(Array<UVOffset>),
+    AddUV1(Array<UVOffset>),
+    AddUV2(Array<UVOffset>),
+    AddUV3(Array<UVOffset>),
+    AddUV4(Array<UVOffset>),
+    Material(Array<MaterialOffset>),
+    /// PMX 2.1
+    Flip(Array<FlipOffset>),
+    /// PMX 2.1
+    Impulse(Array<ImpulseOffset>),
+}
+
+impl<'a, R: Read> Decode<R, &'a PmxHelper<R>> for MorphOffsets {
+    fn decode<B: ByteOrder>(r: &mut R, p: &PmxHelper<R>) -> Result<MorphOffsets> {
+        let offsets = match MorphType::decode::<LE>(r, Nil)? {
+            MorphType::Group => MorphOffsets::Group(Array::decode::<LE>(r, p)?),
+            MorphType::Position => MorphOffsets::Position(Array::decode::<LE>(r, p)?),
+            MorphType::Bone => MorphOffsets::Bone(Array::decode::<LE>(r, p)?),
+            MorphType::UV => MorphOffsets::UV(Array::decode::<LE>(r, p)?),
+            MorphType::AddUV1 => MorphOffsets::AddUV1(Array::decode::<LE>(r, p)?),
+            MorphType::AddUV2 => MorphOffsets::AddUV2(Array::decode::<LE>(r, p)?),
+            MorphType::AddUV3 => MorphOffsets::AddUV3(Array::decode::<LE>(r, p)?),
+            MorphType::AddUV4 => MorphOffsets::AddUV4(Array::decode::<LE>(r, p)?),
+            MorphType::Material => MorphOffsets::Material(Array::decode::<LE>(r, p)?),
+            MorphType::Flip => MorphOffsets::Flip(Array::decode::<LE>(r, p)?),
+            MorphType::Impulse => MorphOffsets::Impulse(Array::decode::<LE>(r, p)?),
+        };
+        Ok(offsets)
+    }
+}
+
+impl<'a, W: Write> Encode<W, &'a PmxEncodeHelper<W>> for MorphOffsets {
+    fn encode<B: ByteOrder>(&self, w: &mut W, p: &PmxEncodeHelper<W>) -> Result<()> {
+        match *self {
+            MorphOffsets::Group(ref x) => (MorphType::Group, x).encode::<LE>(w, p),
+            MorphOffsets::Position(ref x) => (MorphType::Position, x).encode::<LE>(w, p),
+            MorphOffsets::Bone(ref x) => (MorphType::Bone, x).encode::<LE>(w, p),
+            MorphOffsets::UV(ref x) => (MorphType::UV, x).encode::<LE>(w, p),
+            MorphOffsets::AddUV1(ref x) => (MorphType::AddUV1, x).encode::<LE>(w, p),
+            MorphOffsets::AddUV2(ref x) => (MorphType::AddUV2, x).encode::<LE>(w, p),
+            MorphOffsets::AddUV3(ref x) => (MorphType::AddUV3, x).encode::<LE>(w, p),
+            MorphOffsets::AddUV4(ref x) => (MorphType::AddUV4, x).encode::<LE>(w, p),
+            MorphOffsets::Material(ref x) => (MorphType::Material, x).encode::<LE>(w, p),
+            MorphOffsets::Flip(ref x) => (MorphType::Flip, x).encode::<LE>(w, p),
+            MorphOffsets::Impulse(ref x) => (MorphType::Impulse, x).encode::<LE>(w, p),
+        }
+    }
+}
+
+impl<'a, 'b, W: Write, T: Encode<W, &'a PmxEncodeHelper<W>> + BigStruct> Encode<W, &'a PmxEncodeHelper<W>> for (MorphType, &'b Array<T>) {
+    fn encode<B: ByteOrder>(&self, w: &mut W, p: &'a PmxEncodeHelper<W>) -> Result<()> {
+        self.0.encode::<LE>(w, Nil)?;
+        self.1.encode::<LE>(w, p)
+    }
+}
+
+#[derive(Debug, Decode)]
+#[Parameter = "&'a PmxHelper<R>"]
+pub struct GroupOffset {
+    #[Arg = "&p.read_morph_index"]
+    pub morph_id: Index,
+    pub ratio: f32,
+}
+
+impl<'a, W: Write> Encode<W, &'a PmxEncodeHelper<W>> for GroupOffset {
+    fn encode<B: ByteOrder>(&self, w: &mut W, p: &PmxEncodeHelper<W>) -> Result<()> {
+        self.morph_id.encode::<LE>(w, &p.write_morph_index)?;
+        self.ratio.encode::<LE>(w, Nil)
+    }
+}
+
+#[derive(Debug, Decode)]
+#[Parameter = "&'a PmxHelper<R>"]
+pub struct PositionOffset {
+    #[Arg = "&p.read_vertex_index"]
+    pub vertex_id: Index,
+    pub offset: Vec3,
+}
+
+impl<'a, W: Write> Encode<W, &'a PmxEncodeHelper<W>> for PositionOffset {
+    fn encode<B: ByteOrder>(&self, w: &mut W, p: &PmxEncodeHelper<W>) -> Result<()> {
+        self.vertex_id.encode::<LE>(w, &p.write_vertex_index)?;
+        self.offset.encode::<LE>(w, Nil)
+    }
+}
+
+#[derive(Debug, Decode)]
+#[Parameter = "&'a PmxHelper<R>"]
+pub struct BoneOffset {
+    #[Arg = "&p.read_bone_index"]
+    pub bone_id: Index,
+    pub translation: Vec3,
+    /// Quaternion (x, y, z, w)
+    pub rotation: Vec4,
+}
+
+impl<'a, W: Write> Encode<W, &'a PmxEncodeHelper<W>> for BoneOffset {
+    fn encode<B: ByteOrder>(&self, w: &mut W, p: &PmxEncodeHelper<W>) -> Result<()> {
+        self.bone_id.encode::<LE>(w, &p.write_bone_index)?;
+        self.translation.encode::<LE>(w, Nil)?;
+        self.rotation.encode::<LE>(w, Nil)
+    }
+}
+
+/// Used by both UV and additional UV morphs.
+/// Only x and y are meaningful for the UV morph.
+#[derive(Debug, Decode)]
+#[Parameter = "&'a PmxHelper<R>"]
+pub struct UVOffset {
+    #[Arg = "&p.read_vertex_index"]
+    pub vertex_id: Index,
+    pub offset: Vec4,
+}
+
+impl<'a, W: Write> Encode<W, &'a PmxEncodeHelper<W>> for UVOffset {
+    fn encode<B: ByteOrder>(&self, w: &mut W, p: &PmxEncodeHelper<W>) -> Result<()> {
+        self.vertex_id.encode::<LE>(w, &p.write_vertex_index)?;
+        self.offset.encode::<LE>(w, Nil)
+    }
+}
+
+#[derive(Primitive, Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum MaterialOperation {
+    Mul = 0,
+    Add = 1,
+}
+
+impl_decode_mode!(MaterialOperation, u8);
+impl_encode_mode!(MaterialOperation, u8);
+
+#[derive(Debug, Decode)]
+#[Parameter = "&'a PmxHelper<R>"]
+pub struct MaterialOffset {
+    /// -1 means all materials
+    #[Arg = "&p.read_material_index"]
+    pub material_id: Index,
+    pub operation: MaterialOperation,
+    pub diffuse: Vec4,
+    pub specular: Vec3,
+    pub intensity: f32,
+    pub ambient: Vec3,
+    pub edge_color: Vec4,
+    pub edge_size: f32,
+    pub texture_tint: Vec4,
+    pub sphere_tint: Vec4,
+    pub toon_tint: Vec4,
+}
+
+impl<'a, W: Write> Encode<W, &'a PmxEncodeHelper<W>> for MaterialOffset {
+    fn encode<B: ByteOrder>(&self, w: &mut W, p: &PmxEncodeHelper<W>) -> Result<()> {
+        self.material_id.encode::<LE>(w, &p.write_material_index)?;
+        self.operation.encode::<LE>(w, Nil)?;
+        self.diffuse.encode::<LE>(w, Nil)?;
+        self.specular.encode::<LE>(w, Nil)?;
+        self.intensity.encode::<LE>(w, Nil)?;
+        self.ambient.encode::<LE>(w, Nil)?;
+        self.edge_color.encode::<LE>(w, Nil)?;
+        self.edge_size.encode::<LE>(w, Nil)?;
+        self.texture_tint.encode::<LE>(w, Nil)?;
+        self.sphere_tint.encode::<LE>(w, Nil)?;
+        self.toon_tint.encode::<LE>(w, Nil)
+    }
+}
+
+#[derive(Debug, Decode)]
+#[Parameter = "&'a PmxHelper<R>"]
+pub struct FlipOffset {
+    #[Arg = "&p.read_morph_index"]
+    pub morph_id: Index,
+    pub ratio: f32,
+}
+
+impl<'a, W: Write> Encode<W, &'a PmxEncodeHelper<W>> for FlipOffset {
+    fn encode<B: ByteOrder>(&self, w: &mut W, p: &PmxEncodeHelper<W>) -> Result<()> {
+        self.morph_id.encode::<LE>(w, &p.write_morph_index)?;
+        self.ratio.encode::<LE>(w, Nil)
+    }
+}
+
+#[derive(Debug)]
+pub struct ImpulseOffset {
+    pub rigidbody_id: Index,
+    pub local: bool,
+    pub velocity: Vec3,
+    pub torque: Vec3,
+}
+
+impl<'a, R: Read> Decode<R, &'a PmxHelper<R>> for ImpulseOffset {
+    fn decode<B: ByteOrder>(r: &mut R, p: &PmxHelper<R>) -> Result<ImpulseOffset> {
+        let rigidbody_id = Index::decode::<LE>(r, &p.read_rigidbody_index)?;
+        let local = read_bool(r)?;
+        let velocity = Vec3::decode::<LE>(r, Nil)?;
+        let torque = Vec3::decode::<LE>(r, Nil)?;
+        Ok(ImpulseOffset { rigidbody_id, local, velocity, torque })
+    }
+}
+
+impl<'a, W: Write> Encode<W, &'a PmxEncodeHelper<W>> for ImpulseOffset {
+    fn encode<B: ByteOrder>(&self, w: &mut W, p: &PmxEncodeHelper<W>) -> Result<()> {
+        self.rigidbody_id.encode::<LE>(w, &p.write_rigidbody_index)?;
+        (self.local as u8).encode::<LE>(w, Nil)?;
+        self.velocity.encode::<LE>(w, Nil)?;
+        self.torque.encode::<LE>(w, Nil)
+    }
+}
+
+/// 表示枠
+#[derive(Debug)]
+pub struct DisplayFrame {
+    pub name: Name,
+    /// Special frames ("Root" and "表情") cannot be edited in PMXEditor.
+    pub special: bool,
+    pub elements: Array<FrameElement>,
+}
+
+impl<'a, R: Read> Decode<R, &'a PmxHelper<R>> for DisplayFrame {
+    fn decode<B: ByteOrder>(r: &mut R, p: &PmxHelper<R>) -> Result<DisplayFrame> {
+        let name = Name::decode::<LE>(r, p)?;
+        let special = read_bool(r)?;
+        let elements = Array::<FrameElement>::decode::<LE>(r, p)?;
+        Ok(DisplayFrame { name, special, elements })
+    }
+}
+
+impl<'a, W: Write> Encode<W, &'a PmxEncodeHelper<W>> for DisplayFrame {
+    fn encode<B: ByteOrder>(&self, w: &mut W, p: &PmxEncodeHelper<W>) -> Result<()> {
+        self.name.encode::<LE>(w, p)?;
+        (self.special as u8).encode::<LE>(w, Nil)?;
+        self.elements.encode::<LE>(w, p)
+    }
+}
+
+#[derive(Debug)]
+pub enum FrameElement {
+    Bone(Index),
+    Morph(Index),
+}
+
+impl<'a, R: Read> Decode<R, &'a PmxHelper<R>> for FrameElement {
+    fn decode<B: ByteOrder>(r: &mut R, p: &PmxHelper<R>) -> Result<FrameElement> {
+        let element = match u8::decode::<LE>(r, Nil)? {
+            0 => FrameElement::Bone(Index::decode::<LE>(r, &p.read_bone_index)?),
+            1 => FrameElement::Morph(Index::decode::<LE>(r, &p.read_morph_index)?),
+            ty => return Err(err(ErrorKind::InvalidFrameElementType(ty))),
+        };
+        Ok(element)
+    }
+}
+
+impl<'a, W: Write> Encode<W, &'a PmxEncodeHelper<W>> for FrameElement {
+    fn encode<B: ByteOrder>(&self, w: &mut W, p: &PmxEncodeHelper<W>) -> Result<()> {
+        match *self {
+            FrameElement::Bone(ref i) => {
+                w.write_u8(0)?;
+                i.encode::<LE>(w, &p.write_bone_index)
+            }
+            FrameElement::Morph(ref i) => {
+                w.write_u8(1)?;
+                i.encode::<LE>(w, &p.write_morph_index)
+            }
+        }
+    }
+}
+
+#[derive(Primitive, Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum RigidBodyShape {
+    Sphere = 0,
+    Box = 1,
+    Capsule = 2,
+}
+
+impl_decode_mode!(RigidBodyShape, u8);
+impl_encode_mode!(RigidBodyShape, u8);
+
+#[derive(Primitive, Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum PhysicsMode {
+    /// Follow the related bone
+    Static = 0,
+    Dynamic = 1,
+    /// Dynamic rotation, but the position follows the related bone
+    DynamicWithBone = 2,
+}
+
+impl_decode_mode!(PhysicsMode, u8);
+impl_encode_mode!(PhysicsMode, u8);
+
+#[derive(Debug, Decode)]
+#[Parameter = "&'a PmxHelper<R>"]
+pub struct RigidBody {
+    #[Arg = "p"]
+    pub name: Name,
+    /// -1 means no related bone
+    #[Arg = "&p.read_bone_index"]
+    pub bone_id: Index,
+    pub group: u8,
+    /// Bit n set means this body does not collide with group n
+    pub non_collision_mask: u16,
+    pub shape: RigidBodyShape,
+    pub size: Vec3,
+    pub position: Vec3,
+    /// Euler angles in radians
+    pub rotation: Vec3,
+    pub mass: f32,
+    pub linear_damping: f32,
+    pub angular_damping: f32,
+    pub restitution: f32,
+    pub friction: f32,
+    pub physics_mode: PhysicsMode,
+}
+
+impl<'a, W: Write> Encode<W, &'a PmxEncodeHelper<W>> for RigidBody {
+    fn encode<B: ByteOrder>(&self, w: &mut W, p: &PmxEncodeHelper<W>) -> Result<()> {
+        self.name.encode::<LE>(w, p)?;
+        self.bone_id.encode::<LE>(w, &p.write_bone_index)?;
+        self.group.encode::<LE>(w, Nil)?;
+        self.non_collision_mask.encode::<LE>(w, Nil)?;
+        self.shape.encode::<LE>(w, Nil)?;
+        self.size.encode::<LE>(w, Nil)?;
+        self.position.encode::<LE>(w, Nil)?;
+        self.rotation.encode::<LE>(w, Nil)?;
+        self.mass.encode::<LE>(w, Nil)?;
+        self.linear_damping.encode::<LE>(w, Nil)?;
+        self.angular_damping.encode::<LE>(w, Nil)?;
+        self.restitution.encode::<LE>(w, Nil)?;
+        self.friction.encode::<LE>(w, Nil)?;
+        self.physics_mode.encode::<LE>(w, Nil)
+    }
+}
+
+/// Only `Spring6DOF` is available in PMX 2.0
+#[derive(Primitive, Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum JointType {
+    Spring6DOF = 0,
+    SixDOF = 1,
+    P2P = 2,
+    ConeTwist = 3,
+    Slider = 4,
+    Hinge = 5,
+}
+
+impl_decode_mode!(JointType, u8);
+impl_encode_mode!(JointType, u8);
+
+#[derive(Debug, Decode)]
+#[Parameter = "&'a PmxHelper<R>"]
+pub struct Joint {
+    #[Arg = "p"]
+    pub name: Name,
+    pub joint_type: JointType,
+    #[Arg = "&p.read_rigidbody_index"]
+    pub rigidbody_a: Index,
+    #[Arg = "&p.read_rigidbody_index"]
+    pub rigidbody_b: Index,
+    pub position: Vec3,
+    /// Euler angles in radians
+    pub rotation: Vec3,
+    pub position_min: Vec3,
+    pub position_max: Vec3,
+    pub rotation_min: Vec3,
+    pub rotation_max: Vec3,
+    pub position_spring: Vec3,
+    pub rotation_spring: Vec3,
+}
+
+impl<'a, W: Write> Encode<W, &'a PmxEncodeHelper<W>> for Joint {
+    fn encode<B: ByteOrder>(&self, w: &mut W, p: &PmxEncodeHelper<W>) -> Result<()> {
+        self.name.encode::<LE>(w, p)?;
+        self.joint_type.encode::<LE>(w, Nil)?;
+        self.rigidbody_a.encode::<LE>(w, &p.write_rigidbody_index)?;
+        self.rigidbody_b.encode::<LE>(w, &p.write_rigidbody_index)?;
+        self.position.encode::<LE>(w, Nil)?;
+        self.rotation.encode::<LE>(w, Nil)?;
+        self.position_min.encode::<LE>(w, Nil)?;
+        self.position_max.encode::<LE>(w, Nil)?;
+        self.rotation_min.encode::<LE>(w, Nil)?;
+        self.rotation_max.encode::<LE>(w, Nil)?;
+        self.position_spring.encode::<LE>(w, Nil)?;
+        self.rotation_spring.encode::<LE>(w, Nil)
+    }
+}
+
+#[derive(Primitive, Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum SoftBodyShape {
+    TriMesh = 0,
+    Rope = 1,
+}
+
+impl_decode_mode!(SoftBodyShape, u8);
+impl_encode_mode!(SoftBodyShape, u8);
+
+#[derive(EnumFlags, Debug, Clone, Copy)]
+#[repr(u8)]
+pub enum SoftBodyFlags {
+    BLink = 0x01,
+    ClusterCreation = 0x02,
+    LinkCrossing = 0x04,
+}
+
+impl_decode_modeset!(SoftBodyFlags, u8);
+impl_encode_modeset!(SoftBodyFlags, u8);
+
+#[derive(Primitive, Debug, Clone, Copy, PartialEq)]
+#[repr(i32)]
+pub enum AeroModel {
+    VertexPoint = 0,
+    VertexTwoSided = 1,
+    VertexOneSided = 2,
+    FaceTwoSided = 3,
+    FaceOneSided = 4,
+}
+
+impl_decode_mode!(AeroModel, i32);
+impl_encode_mode!(AeroModel, i32);
+
+/// Field names follow btSoftBody::Config
+#[derive(Debug, Decode)]
+pub struct SoftBodyConfig {
+    /// Velocities correction factor (Baumgarte)
+    pub vcf: f32,
+    /// Damping coefficient
+    pub dp: f32,
+    /// Drag coefficient
+    pub dg: f32,
+    /// Lift coefficient
+    pub lf: f32,
+    /// Pressure coefficient
+    pub pr: f32,
+    /// Volume conversation coefficient
+    pub vc: f32,
+    /// Dynamic friction coefficient
+    pub df: f32,
+    /// Pose matching coefficient
+    pub mt: f32,
+    /// Rigid contacts hardness
+    pub chr: f32,
+    /// Kinetic contacts hardness
+    pub khr: f32,
+    /// Soft contacts hardness
+    pub shr: f32,
+    /// Anchors hardness
+    pub ahr: f32,
+}
+
+impl<W: Write> Encode<W, Nil> for SoftBodyConfig {
+    fn encode<B: ByteOrder>(&self, w: &mut W, _p: Nil) -> Result<()> {
+        [self.vcf, self.dp, self.dg, self.lf, self.pr, self.vc, self.df, self.mt, self.chr, self.khr, self.shr, self.ahr]
+            .iter()
+            .try_for_each(|x| x.encode::<LE>(w, Nil))
+    }
+}
+
+#[derive(Debug, Decode)]
+pub struct SoftBodyCluster {
+    pub srhr: f32,
+    pub skhr: f32,
+    pub sshr: f32,
+    pub sr_splt: f32,
+    pub sk_splt: f32,
+    pub ss_splt: f32,
+}
+
+impl<W: Write> Encode<W, Nil> for SoftBodyCluster {
+    fn encode<B: ByteOrder>(&self, w: &mut W, _p: Nil) -> Result<()> {
+        [self.srhr, self.skhr, self.sshr, self.sr_splt, self.sk_splt, self.ss_splt]
+            .iter()
+            .try_for_each(|x| x.encode::<LE>(w, Nil))
+    }
+}
+
+#[derive(Debug, Decode)]
+pub struct SoftBodyIteration {
+    pub v_it: i32,
+    pub p_it: i32,
+    pub d_it: i32,
+    pub c_it: i32,
+}
+
+impl<W: Write> Encode<W, Nil> for SoftBodyIteration {
+    fn encode<B: ByteOrder>(&self, w: &mut W, _p: Nil) -> Result<()> {
+        [self.v_it, self.p_it, self.d_it, self.c_it].iter().try_for_each(|x| x.encode::<LE>(w, Nil))
+    }
+}
+
+#[derive(Debug, Decode)]
+pub struct SoftBodyMaterial {
+    /// Linear stiffness coefficient
+    pub lst: f32,
+    /// Area/Angular stiffness coefficient
+    pub ast: f32,
+    /// Volume stiffness coefficient
+    pub vst: f32,
+}
+
+impl<W: Write> Encode<W, Nil> for SoftBodyMaterial {
+    fn encode<B: ByteOrder>(&self, w: &mut W, _p: Nil) -> Result<()> {
+        [self.lst, self.ast, self.vst].iter().try_for_each(|x| x.encode::<LE>(w, Nil))
+    }
+}
+
+#[derive(Debug)]
+pub struct AnchorRigidBody {
+    pub rigidbody_id: Index,
+    pub vertex_id: Index,
+    pub near_mode: bool,
+}
+
+impl<'a, R: Read> Decode<R, &'a PmxHelper<R>> for AnchorRigidBody {
+    fn decode<B: ByteOrder>(r: &mut R, p: &PmxHelper<R>) -> Result<AnchorRigidBody> {
+        let rigidbody_id = Index::decode::<LE>(r, &p.read_rigidbody_index)?;
+        let vertex_id = Index::decode::<LE>(r, &p.read_vertex_index)?;
+        let near_mode = read_bool(r)?;
+        Ok(AnchorRigidBody { rigidbody_id, vertex_id, near_mode })
+    }
+}
+
+impl<'a, W: Write> Encode<W, &'a PmxEncodeHelper<W>> for AnchorRigidBody {
+    fn encode<B: ByteOrder>(&self, w: &mut W, p: &PmxEncodeHelper<W>) -> Result<()> {
+        self.rigidbody_id.encode::<LE>(w, &p.write_rigidbody_index)?;
+        self.vertex_id.encode::<LE>(w, &p.write_vertex_index)?;
+        (self.near_mode as u8).encode::<LE>(w, Nil)
+    }
+}
+
+/// PMX 2.1
+#[derive(Debug, Decode)]
+#[Parameter = "&'a PmxHelper<R>"]
+pub struct SoftBody {
+    #[Arg = "p"]
+    pub name: Name,
+    pub shape: SoftBodyShape,
+    #[Arg = "&p.read_material_index"]
+    pub material_id: Index,
+    pub group: u8,
+    /// Bit n set means this body does not collide with group n
+    pub non_collision_mask: u16,
+    #[Arg = "p"]
+    pub flags: ModeSet<SoftBodyFlags>,
+    pub b_link_distance: i32,
+    pub num_clusters: i32,
+    pub total_mass: f32,
+    pub collision_margin: f32,
+    pub aero_model: AeroModel,
+    pub config: SoftBodyConfig,
+    pub cluster: SoftBodyCluster,
+    pub iteration: SoftBodyIteration,
+    pub material: SoftBodyMaterial,
+    #[Arg = "p"]
+    pub anchors: Array<AnchorRigidBody>,
+    #[Arg = "&p.read_vertex_index"]
+    pub pin_vertices: Array<Index>,
+}
+
+impl<'a, W: Write> Encode<W, &'a PmxEncodeHelper<W>> for SoftBody {
+    fn encode<B: ByteOrder>(&self, w: &mut W, p: &PmxEncodeHelper<W>) -> Result<()> {
+        self.name.encode::<LE>(w, p)?;
+        self.shape.encode::<LE>(w, Nil)?;
+        self.material_id.encode::<LE>(w, &p.write_material_index)?;
+        self.group.encode::<LE>(w, Nil)?;
+        self.non_collision_mask.encode::<LE>(w, Nil)?;
+        self.flags.encode::<LE>(w, Nil)?;
+        self.b_link_distance.encode::<LE>(w, Nil)?;
+        self.num_clusters.encode::<LE>(w, Nil)?;
+        self.total_mass.encode::<LE>(w, Nil)?;
+        self.collision_margin.encode::<LE>(w, Nil)?;
+        self.aero_model.encode::<LE>(w, Nil)?;
+        self.config.encode::<LE>(w, Nil)?;
+        self.cluster.encode::<LE>(w, Nil)?;
+        self.iteration.encode::<LE>(w, Nil)?;
+        self.material.encode::<LE>(w, Nil)?;
+        self.anchors.encode::<LE>(w, p)?;
+        self.pin_vertices.encode::<LE>(w, &p.write_vertex_index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Vector2, Vector3, Vector4};
+    use std::io::Cursor;
+
+    type Rdr = Cursor<Vec<u8>>;
+
+    fn helper() -> PmxHelper<Rdr> {
+        helper_with_version(2.0)
+    }
+
+    fn helper_with_version<R: Read>(version: f32) -> PmxHelper<R> {
+        PmxHelper::from_header(&helper_header(version), false).unwrap()
+    }
+
+    fn helper_header(version: f32) -> Header {
+        Header {
+            version,
+            dummy: 8,
+            encode: 1,
+            additional: 0,
+            vertex_index_size: 2,
+            texture_index_size: 1,
+            material_index_size: 1,
+            bone_index_size: 2,
+            morph_index_size: 2,
+            rigidbody_index_size: 2,
+        }
+    }
+
+    fn write_str(w: &mut Vec<u8>, s: &str) {
+        w.write_u32::<LE>(s.len() as u32).unwrap();
+        w.write_all(s.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn decode_vertex_morph() {
+        let mut data = Vec::new();
+        write_str(&mut data, "あ");
+        write_str(&mut data, "a");
+        data.write_u8(MorphPanel::Mouth as u8).unwrap();
+        data.write_u8(MorphType::Position as u8).unwrap();
+        data.write_u32::<LE>(2).unwrap();
+        for &(id, y) in &[(3u16, 0.5f32), (0xFFFF, -1.0)] {
+            data.write_u16::<LE>(id).unwrap();
+            for &f in &[0.0, y, 0.0] {
+                data.write_f32::<LE>(f).unwrap();
+            }
+        }
+
+        let morph = Morph::decode::<LE>(&mut Cursor::new(data), &helper()).unwrap();
+        assert_eq!(morph.name.jp.0, "あ");
+        assert_eq!(morph.name.en.0, "a");
+        assert_eq!(morph.panel, MorphPanel::Mouth);
+        match morph.offsets {
+            MorphOffsets::Position(Array(ref offsets)) => {
+                assert_eq!(offsets.len(), 2);
+                assert_eq!(offsets[0].vertex_id.0, 3);
+                assert_eq!((offsets[0].offset.0).y, 0.5);
+                // Vertex indices of 2 bytes are unsigned
+                assert_eq!(offsets[1].vertex_id.0, 65535);
+            }
+            ref other => panic!("unexpected offsets {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decode_common_toon() {
+        let header = Header { texture_index_size: 2, ..helper_header(2.0) };
+        let p = PmxHelper::<Rdr>::from_header(&header, false).unwrap();
+        let mut data = Vec::new();
+        write_str(&mut data, "肌");
+        write_str(&mut data, "skin");
+        data.extend_from_slice(&[0; 44]);
+        data.write_u8(DrawModeFlags::TwoSided as u8).unwrap();
+        data.extend_from_slice(&[0; 20]);
+        data.write_i16::<LE>(0).unwrap();
+        data.write_i16::<LE>(-1).unwrap();
+        data.write_u8(SphereMode::Disabled as u8).unwrap();
+        data.write_u8(ToonMode::Common as u8).unwrap();
+        // one byte instead of a texture index
+        data.write_u8(3).unwrap();
+        write_str(&mut data, "");
+        data.write_i32::<LE>(6).unwrap();
+
+        let len = data.len() as u64;
+        let mut r = Cursor::new(data);
+        let m = Material::decode::<LE>(&mut r, &p).unwrap();
+        assert_eq!((m.toon_mode, m.toon_texture_id.0, m.num_vertex_indices), (ToonMode::Common, 3, 6));
+        assert_eq!(r.position(), len);
+    }
+
+    #[test]
+    fn decode_display_frame() {
+        let mut data = Vec::new();
+        write_str(&mut data, "表情");
+        write_str(&mut data, "Exp");
+        data.write_u8(1).unwrap();
+        data.write_u32::<LE>(2).unwrap();
+        data.write_u8(1).unwrap();
+        data.write_u16::<LE>(7).unwrap();
+        data.write_u8(0).unwrap();
+        data.write_u16::<LE>(2).unwrap();
+
+        let frame = DisplayFrame::decode::<LE>(&mut Cursor::new(data), &helper()).unwrap();
+        assert!(frame.special);
+        match (frame.elements.0)[..] {
+            [FrameElement::Morph(Index(7)), FrameElement::Bone(Index(2))] => {}
+            ref other => panic!("unexpected elements {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decode_rigid_body() {
+        let mut data = Vec::new();
+        write_str(&mut data, "頭");
+        write_str(&mut data, "head");
+        data.write_u16::<LE>(0xFFFF).unwrap();
+        data.write_u8(3).unwrap();
+        data.write_u16::<LE>(0x0006).unwrap();
+        data.write_u8(RigidBodyShape::Capsule as u8).unwrap();
+        for i in 0..9 {
+            data.write_f32::<LE>(i as f32).unwrap();
+        }
+        for &f in &[1.0, 0.5, 0.5, 0.0, 0.5] {
+            data.write_f32::<LE>(f).unwrap();
+        }
+        data.write_u8(PhysicsMode::DynamicWithBone as u8).unwrap();
+
+        let len = data.len() as u64;
+        let mut r = Cursor::new(data);
+        let body = RigidBody::decode::<LE>(&mut r, &helper()).unwrap();
+        assert_eq!(r.position(), len);
+        assert_eq!(body.name.en.0, "head");
+        assert_eq!((body.bone_id.0, body.group, body.non_collision_mask), (-1, 3, 0x0006));
+        assert_eq!(body.shape, RigidBodyShape::Capsule);
+        assert_eq!((body.size.0.x, body.position.0.x, body.rotation.0.z), (0.0, 3.0, 8.0));
+        assert_eq!((body.mass, body.friction), (1.0, 0.5));
+        assert_eq!(body.physics_mode, PhysicsMode::DynamicWithBone);
+    }
+
+    #[test]
+    fn decode_joint() {
+        let mut data = Vec::new();
+        write_str(&mut data, "髪");
+        write_str(&mut data, "hair");
+        data.write_u8(JointType::Spring6DOF as u8).unwrap();
+        data.write_u16::<LE>(1).unwrap();
+        data.write_u16::<LE>(2).unwrap();
+        for i in 0..24 {
+            data.write_f32::<LE>(i as f32).unwrap();
+        }
+
+        let len = data.len() as u64;
+        let mut r = Cursor::new(data);
+        let joint = Joint::decode::<LE>(&mut r, &helper()).unwrap();
+        assert_eq!(r.position(), len);
+        assert_eq!(joint.name.jp.0, "髪");
+        assert_eq!(joint.joint_type, JointType::Spring6DOF);
+        assert_eq!((joint.rigidbody_a.0, joint.rigidbody_b.0), (1, 2));
+        assert_eq!((joint.position.0.x, joint.rotation_max.0.x, joint.rotation_spring.0.z), (0.0, 15.0, 23.0));
+    }
+
+    #[test]
+    fn soft_bodies_since_2_1() {
+        let empty_sections = |n: usize| vec![0u8; 4 * n];
+
+        let load = |n, version| Model::default().load(&mut Tracker::new(Cursor::new(empty_sections(n))), &helper_with_version(version));
+
+        load(9, 2.0).unwrap();
+
+        let e = load(9, 2.1).unwrap_err();
+        assert_eq!(e.section(), Some("soft bodies"));
+        assert_eq!(e.offset(), 36);
+
+        let mut rdr = Tracker::new(Cursor::new(empty_sections(10)));
+        Model::default().load(&mut rdr, &helper_with_version(2.1)).unwrap();
+        assert_eq!(rdr.offset, 40);
+    }
+
+    fn v2(x: f32, y: f32) -> Vec2 {
+        Vec2(Vector2::new(x, y))
+    }
+
+    pub(super) fn v3(x: f32, y: f32, z: f32) -> Vec3 {
+        Vec3(Vector3::new(x, y, z))
+    }
+
+    fn v4(x: f32, y: f32, z: f32, w: f32) -> Vec4 {
+        Vec4(Vector4::new(x, y, z, w))
+    }
+
+    fn name(jp: &str, en: &str) -> Name {
+        Name { jp: PmxString(jp.to_owned()), en: PmxString(en.to_owned()) }
+    }
+
+    pub(super) fn vertex(position: Vec3, bone_weight: BoneWeight, additional: usize) -> Vertex {
+        Vertex {
+            position,
+            normal: v3(0.0, 1.0, 0.0),
+            uv: v2(0.25, 0.75),
+            additional: Array((0..additional).map(|i| v4(i as f32, 0.0, 0.0, 1.0)).collect()),
+            bone_weight,
+            edge_scale: 1.0,
+        }
+    }
+
+    fn bone(name_jp: &str, parent_id: i32, flags: BitFlags<BoneFlags>, extra: BoneExtraInfo) -> Bone {
+        Bone {
+            name: PmxString(name_jp.to_owned()),
+            name_en: PmxString(String::new()),
+            position: v3(0.0, parent_id as f32 + 1.0, 0.0),
+            parent_id: Index(parent_id),
+            deform_depth: 0,
+            flags: ModeSet::new(flags),
+            extra,
+        }
+    }
+
+    fn no_extra() -> BoneExtraInfo {
+        BoneExtraInfo {
+            tail: TailTarget::Offset(v3(0.0, 1.0, 0.0)),
+            append: None,
+            fixed_axes: None,
+            local_axes: None,
+            key_value: None,
+            ik: None,
+        }
+    }
+
+    /// A small model which covers every section
+    pub(super) fn sample_file(encode: u8, index_size: u8, version: f32) -> PmxFile {
+        use self::BoneFlags::*;
+        let header = Header {
+            version,
+            dummy: 8,
+            encode,
+            additional: 1,
+            vertex_index_size: index_size,
+            texture_index_size: index_size,
+            material_index_size: index_size,
+            bone_index_size: index_size,
+            morph_index_size: index_size,
+            rigidbody_index_size: index_size,
+        };
+        let vertices = vec![
+            vertex(v3(0.0, 0.0, 0.0), BoneWeight::BDEF1 { index: 0 }, 1),
+            vertex(v3(1.0, 0.0, 0.0), BoneWeight::BDEF2 { indices: [0, 1], weight: 0.5 }, 1),
+            vertex(v3(0.0, 1.0, 0.0), BoneWeight::BDEF4 { indices: [0, 1, 2, -1], weights: [0.25, 0.25, 0.5, 0.0] }, 1),
+            vertex(
+                v3(0.0, 0.0, 1.0),
+                BoneWeight::SDEF { indices: [1, 2], weight: 0.75, c: v3(0.0, 1.0, 0.0), r0: v3(0.0, 1.5, 0.0), r1: v3(0.0, 0.5, 0.0) },
+                1,
+            ),
+            vertex(v3(1.0, 1.0, 1.0), BoneWeight::QDEF { indices: [0, 1, -1, -1], weights: [0.5, 0.5, 0.0, 0.0] }, 1),
+        ];
+        let face_indices = vec![0, 1, 2, 2, 3, 4];
+        let material = Material {
+            name: name("肌", "skin"),
+            diffuse: v4(1.0, 1.0, 1.0, 1.0),
+            specular: v3(0.1, 0.1, 0.1),
+            intensity: 5.0,
+            ambient: v3(0.5, 0.5, 0.5),
+            draw_mode: ModeSet::new(DrawModeFlags::TwoSided | DrawModeFlags::DrawEdge),
+            edge_color: v4(0.0, 0.0, 0.0, 1.0),
+            edge_size: 1.0,
+            texture_id: Index(0),
+            sphere_texture_id: Index(-1),
+            sphere_mode: SphereMode::Mul,
+            toon_mode: ToonMode::Common,
+            toon_texture_id: Index(3),
+            memo: PmxString("メモ".to_owned()),
+            num_vertex_indices: 6,
+        };
+        let ik_link = IKLink { bone_id: Index(1), limits: Some((v3(-3.0, 0.0, 0.0), v3(-0.01, 0.0, 0.0))) };
+        let bones = vec![
+            bone("センター", -1, CanRotate | CanTranslate, no_extra()),
+            bone(
+                "腕",
+                0,
+                TargetMode | CanRotate | AppendRotate | LocalAxes,
+                BoneExtraInfo {
+                    tail: TailTarget::Bone(Index(2)),
+                    append: Some(AppendTransform { parent: Index(0), ratio: -0.5, rotate: true, translate: false, local: false }),
+                    local_axes: Some(super::LocalAxes { x: v3(1.0, 0.0, 0.0), z: v3(0.0, 0.0, 1.0) }),
+                    ..no_extra()
+                },
+            ),
+            bone(
+                "足ＩＫ",
+                0,
+                CanRotate | IK | AxesFixed | DeformOuterParent,
+                BoneExtraInfo {
+                    fixed_axes: Some(v3(1.0, 0.0, 0.0)),
+                    key_value: Some(7),
+                    ik: Some(IkSolver { target: Index(1), iterations: 40, limit_angle: 2.0, links: Array(vec![ik_link]) }),
+                    ..no_extra()
+                },
+            ),
+        ];
+        let morphs = vec![
+            Morph {
+                name: name("あ", "a"),
+                panel: MorphPanel::Mouth,
+                offsets: MorphOffsets::Position(Array(vec![PositionOffset { vertex_id: Index(4), offset: v3(0.0, 0.1, 0.0) }])),
+            },
+            Morph {
+                name: name("腕", "arm"),
+                panel: MorphPanel::Other,
+                offsets: MorphOffsets::Bone(Array(vec![BoneOffset { bone_id: Index(1), translation: v3(0.0, 0.0, 0.0), rotation: v4(0.0, 0.0, 0.0, 1.0) }])),
+            },
+            Morph {
+                name: name("UV", "uv"),
+                panel: MorphPanel::Other,
+                offsets: MorphOffsets::AddUV1(Array(vec![UVOffset { vertex_id: Index(0), offset: v4(0.1, 0.2, 0.3, 0.4) }])),
+            },
+            Morph {
+                name: name("材質", "material"),
+                panel: MorphPanel::Other,
+                offsets: MorphOffsets::Material(Array(vec![MaterialOffset {
+                    material_id: Index(-1),
+                    operation: MaterialOperation::Add,
+                    diffuse: v4(0.1, 0.1, 0.1, 0.0),
+                    specular: v3(0.0, 0.0, 0.0),
+                    intensity: 0.0,
+                    ambient: v3(0.0, 0.0, 0.0),
+                    edge_color: v4(0.0, 0.0, 0.0, 0.0),
+                    edge_size: 0.0,
+                    texture_tint: v4(0.0, 0.0, 0.0, 0.0),
+                    sphere_tint: v4(0.0, 0.0, 0.0, 0.0),
+                    toon_tint: v4(0.0, 0.0, 0.0, 0.0),
+                }])),
+            },
+            Morph {
+                name: name("グループ", "group"),
+                panel: MorphPanel::Eye,
+                offsets: MorphOffsets::Group(Array(vec![GroupOffset { morph_id: Index(0), ratio: 0.5 }])),
+            },
+            Morph {
+                name: name("フリップ", "flip"),
+                panel: MorphPanel::Eyebrow,
+                offsets: MorphOffsets::Flip(Array(vec![FlipOffset { morph_id: Index(4), ratio: 1.0 }])),
+            },
+            Morph {
+                name: name("インパルス", "impulse"),
+                panel: MorphPanel::Other,
+                offsets: MorphOffsets::Impulse(Array(vec![ImpulseOffset { rigidbody_id: Index(0), local: true, velocity: v3(0.0, 1.0, 0.0), torque: v3(0.0, 0.0, 0.0) }])),
+            },
+        ];
+        let display_frames = vec![
+            DisplayFrame { name: name("Root", "Root"), special: true, elements: Array(vec![FrameElement::Bone(Index(0))]) },
+            DisplayFrame { name: name("表情", "Exp"), special: true, elements: Array(vec![FrameElement::Morph(Index(0)), FrameElement::Morph(Index(4))]) },
+        ];
+        let rigid_bodies = vec![RigidBody {
+            name: name("頭", "head"),
+            bone_id: Index(0),
+            group: 1,
+            non_collision_mask: 0xFFFE,
+            shape: RigidBodyShape::Capsule,
+            size: v3(1.0, 2.0, 0.0),
+            position: v3(0.0, 1.0, 0.0),
+            rotation: v3(0.0, 0.0, 0.0),
+            mass: 1.0,
+            linear_damping: 0.5,
+            angular_damping: 0.5,
+            restitution: 0.0,
+            friction: 0.5,
+            physics_mode: PhysicsMode::DynamicWithBone,
+        }];
+        let joints = vec![Joint {
+            name: name("首", "neck"),
+            joint_type: JointType::Spring6DOF,
+            rigidbody_a: Index(0),
+            rigidbody_b: Index(-1),
+            position: v3(0.0, 1.0, 0.0),
+            rotation: v3(0.0, 0.0, 0.0),
+            position_min: v3(0.0, 0.0, 0.0),
+            position_max: v3(0.0, 0.0, 0.0),
+            rotation_min: v3(-0.1, -0.1, -0.1),
+            rotation_max: v3(0.1, 0.1, 0.1),
+            position_spring: v3(0.0, 0.0, 0.0),
+            rotation_spring: v3(10.0, 10.0, 10.0),
+        }];
+        let soft_bodies = if version >= 2.1 {
+            vec![SoftBody {
+                name: name("布", "cloth"),
+                shape: SoftBodyShape::TriMesh,
+                material_id: Index(0),
+                group: 2,
+                non_collision_mask: 0,
+                flags: ModeSet::new(SoftBodyFlags::BLink | SoftBodyFlags::ClusterCreation),
+                b_link_distance: 2,
+                num_clusters: 4,
+                total_mass: 1.0,
+                collision_margin: 0.05,
+                aero_model: AeroModel::FaceTwoSided,
+                config: SoftBodyConfig { vcf: 1.0, dp: 0.0, dg: 0.0, lf: 0.0, pr: 0.0, vc: 0.0, df: 0.2, mt: 0.0, chr: 1.0, khr: 0.1, shr: 1.0, ahr: 0.7 },
+                cluster: SoftBodyCluster { srhr: 0.1, skhr: 1.0, sshr: 0.5, sr_splt: 0.5, sk_splt: 0.5, ss_splt: 0.5 },
+                iteration: SoftBodyIteration { v_it: 0, p_it: 1, d_it: 0, c_it: 4 },
+                material: SoftBodyMaterial { lst: 1.0, ast: 1.0, vst: 1.0 },
+                anchors: Array(vec![AnchorRigidBody { rigidbody_id: Index(0), vertex_id: Index(1), near_mode: false }]),
+                pin_vertices: Array(vec![Index(0), Index(3)]),
+            }]
+        } else {
+            Vec::new()
+        };
+        PmxFile {
+            magic: *b"PMX ",
+            header,
+            model_name: name("サンプル", "Sample"),
+            comment: name("コメント\r\n", "Comment"),
+            model: Model {
+                vertices: Array(vertices),
+                face_indices: Array(face_indices),
+                textures: Array(vec![Texture(PmxString("tex\\skin.png".to_owned()))]),
+                materials: Array(vec![material]),
+                bones: Array(bones),
+                morphs: Array(morphs),
+                display_frames: Array(display_frames),
+                rigid_bodies: Array(rigid_bodies),
+                joints: Array(joints),
+                soft_bodies: Array(soft_bodies),
+            },
+            missing: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn save_round_trip() {
+        for &(encode, index_size, version) in &[(0, 1, 2.0), (1, 2, 2.0), (0, 4, 2.1), (1, 1, 2.1)] {
+            let mut saved = Vec::new();
+            sample_file(encode, index_size, version).save(&mut saved).unwrap();
+            let loaded = PmxFile::load(&mut Cursor::new(&saved)).unwrap();
+            assert_eq!(loaded.model_name.jp.0, "サンプル");
+            assert_eq!(loaded.model.bones.0[2].name.0, "足ＩＫ");
+            assert_eq!(loaded.model.morphs.0.len(), 7);
+            assert_eq!(loaded.model.soft_bodies.0.len(), if version >= 2.1 { 1 } else { 0 });
+
+            let mut resaved = Vec::new();
+            loaded.save(&mut resaved).unwrap();
+            assert_eq!(saved, resaved);
+        }
+    }
+
+    #[test]
+    fn load_from_bytes_and_reader() {
+        let mut saved = Vec::new();
+        sample_file(1, 2, 2.0).save(&mut saved).unwrap();
+        let from_bytes = PmxFile::from_bytes(&saved).unwrap();
+        let from_reader = PmxFile::from_reader(&mut Cursor::new(&saved)).unwrap();
+        for loaded in &[from_bytes, from_reader] {
+            let mut resaved = Vec::new();
+            loaded.save(&mut resaved).unwrap();
+            assert_eq!(saved, resaved);
+        }
+        assert!(PmxFile::from_bytes(&saved[..saved.len() - 1]).is_err());
+    }
+
+    fn write_f32s(w: &mut Vec<u8>, fs: &[f32]) {
+        fs.iter().for_each(|&f| w.write_f32::<LE>(f).unwrap());
+    }
+
+    /// A whole PMX 2.0 file written field by field after the format, not with the encoder.
+    /// The index sizes differ, and the material has a common toon.
+    fn handmade_file() -> Vec<u8> {
+        let mut w = Vec::new();
+        w.extend_from_slice(b"PMX ");
+        // version, header size, UTF-8, no additional UVs,
+        // vertex, texture, material, bone, morph and rigid body index sizes
+        w.write_f32::<LE>(2.0).unwrap();
+        w.extend_from_slice(&[8, 1, 0, 2, 2, 1, 2, 1, 1]);
+        for s in &["手作り", "handmade", "コメント", "comment"] {
+            write_str(&mut w, s);
+        }
+
+        w.write_u32::<LE>(3).unwrap();
+        for i in 0..3 {
+            write_f32s(&mut w, &[i as f32, 0.0, 0.0, 0.0, 0.0, 1.0, 0.5, 0.5]);
+            if i == 0 {
+                w.write_u8(0).unwrap();
+                w.write_u16::<LE>(0).unwrap();
+            } else {
+                w.write_u8(1).unwrap();
+                w.write_u16::<LE>(0).unwrap();
+                w.write_u16::<LE>(0).unwrap();
+                w.write_f32::<LE>(0.25).unwrap();
+            }
+            w.write_f32::<LE>(1.0).unwrap();
+        }
+        w.write_u32::<LE>(3).unwrap();
+        [0u16, 1, 2].iter().for_each(|&i| w.write_u16::<LE>(i).unwrap());
+        w.write_u32::<LE>(1).unwrap();
+        write_str(&mut w, "tex.png");
+
+        w.write_u32::<LE>(1).unwrap();
+        write_str(&mut w, "材質");
+        write_str(&mut w, "material");
+        write_f32s(&mut w, &[1.0, 1.0, 1.0, 1.0, 0.0, 0.0, 0.0, 5.0, 0.5, 0.5, 0.5]);
+        w.write_u8(0x11).unwrap();
+        write_f32s(&mut w, &[0.0, 0.0, 0.0, 1.0, 1.0]);
+        w.write_u16::<LE>(0).unwrap();
+        w.write_u16::<LE>(0xFFFF).unwrap();
+        // no sphere, common toon03.bmp in one byte
+        w.extend_from_slice(&[0, 1, 2]);
+        write_str(&mut w, "");
+        w.write_i32::<LE>(3).unwrap();
+
+        w.write_u32::<LE>(1).unwrap();
+        write_str(&mut w, "センター");
+        write_str(&mut w, "center");
+        write_f32s(&mut w, &[0.0, 0.0, 0.0]);
+        w.write_u16::<LE>(0xFFFF).unwrap();
+        w.write_i32::<LE>(0).unwrap();
+        // rotate, translate, visible and controllable, with a tail offset
+        w.write_u16::<LE>(0x001E).unwrap();
+        write_f32s(&mut w, &[0.0, 1.0, 0.0]);
+
+        w.write_u32::<LE>(1).unwrap();
+        write_str(&mut w, "あ");
+        write_str(&mut w, "a");
+        w.extend_from_slice(&[3, MorphType::Position as u8]);
+        w.write_u32::<LE>(1).unwrap();
+        w.write_u16::<LE>(2).unwrap();
+        write_f32s(&mut w, &[0.0, 0.1, 0.0]);
+
+        w.write_u32::<LE>(1).unwrap();
+        write_str(&mut w, "Root");
+        write_str(&mut w, "Root");
+        w.write_u8(1).unwrap();
+        w.write_u32::<LE>(1).unwrap();
+        w.write_u8(0).unwrap();
+        w.write_u16::<LE>(0).unwrap();
+
+        w.write_u32::<LE>(1).unwrap();
+        write_str(&mut w, "体");
+        write_str(&mut w, "body");
+        w.write_u16::<LE>(0).unwrap();
+        w.write_u8(0).unwrap();
+        w.write_u16::<LE>(0xFFFE).unwrap();
+        w.write_u8(RigidBodyShape::Box as u8).unwrap();
+        write_f32s(&mut w, &[1.0, 1.0, 1.0, 0.0, 0.5, 0.0, 0.0, 0.0, 0.0, 1.0, 0.5, 0.5, 0.0, 0.5]);
+        w.write_u8(PhysicsMode::Static as u8).unwrap();
+
+        w.write_u32::<LE>(1).unwrap();
+        write_str(&mut w, "継ぎ目");
+        write_str(&mut w, "joint");
+        w.extend_from_slice(&[JointType::Spring6DOF as u8, 0, 0]);
+        write_f32s(&mut w, &[0.0; 24]);
+        w
+    }
+
+    #[test]
+    fn save_handmade_file_unchanged() {
+        let original = handmade_file();
+        let (loaded, warnings) = PmxFile::load_with(&mut &original[..], LoadMode::Normal).unwrap();
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        let material = &loaded.model.materials.0[0];
+        assert_eq!((material.toon_mode, material.toon_texture_id.0), (ToonMode::Common, 2));
+        assert_eq!(loaded.model.joints.0[0].name.en.0, "joint");
+
+        let mut saved = Vec::new();
+        loaded.save(&mut saved).unwrap();
+        assert_eq!(saved, original);
+    }
+
+    #[test]
+    fn header_accessors() {
+        let pmx = PmxFile::from_bytes(&handmade_file()).unwrap();
+        assert_eq!((pmx.version(), pmx.encoding(), pmx.additional()), (2.0, TextEncoding::Utf8, 0));
+        assert_eq!(pmx.index_sizes(), IndexSizes { vertex: 2, texture: 2, material: 1, bone: 2, morph: 1, rigid_body: 1 });
+    }
+
+    /// Run with `cargo test -- --ignored` after `git lfs pull`
+    #[test]
+    #[ignore = "needs the LFS asset"]
+    fn save_bundled_model_unchanged() {
+        let original = ::std::fs::read("asset/ニコニ立体ちゃん/Alicia_solid.pmx").unwrap();
+        assert!(original.starts_with(b"PMX "), "the model is a Git LFS pointer, run `git lfs pull`");
+        let loaded = PmxFile::from_bytes(&original).unwrap();
+        let mut saved = Vec::new();
+        loaded.save(&mut saved).unwrap();
+        assert!(saved == original, "the saved model differs from the original");
+    }
+
+    #[test]
+    fn load_vertices_across_buffer_refills() {
+        let mut file = sample_file(0, 2, 2.0);
+        let weights = |i: i32| match i % 3 {
+            0 => BoneWeight::BDEF1 { index: 0 },
+            1 => BoneWeight::SDEF { indices: [1, 2], weight: 0.5, c: v3(0.0, 1.0, 0.0), r0: v3(0.0, 1.5, 0.0), r1: v3(0.0, 0.5, 0.0) },
+            _ => BoneWeight::BDEF4 { indices: [0, 1, 2, -1], weights: [0.25, 0.25, 0.5, 0.0] },
+        };
+        file.model.vertices = Array((0..5000).map(|i| vertex(v3(i as f32, 0.0, 0.0), weights(i), 1)).collect());
+        file.model.face_indices = Array((0..4998).flat_map(|i| vec![i, i + 1, i + 2]).collect());
+        file.model.materials.0[0].num_vertex_indices = 3 * 4998;
+        let mut saved = Vec::new();
+        file.save(&mut saved).unwrap();
+        assert!(saved.len() > 4 * bulk::CHUNK);
+
+        let loaded = PmxFile::from_bytes(&saved).unwrap();
+        assert_eq!(loaded.model.vertices.0.len(), 5000);
+        assert_eq!(loaded.model.vertices.0[4321].position.0.x, 4321.0);
+        assert_eq!(loaded.model.face_indices.0, file.model.face_indices.0);
+        let mut resaved = Vec::new();
+        loaded.save(&mut resaved).unwrap();
+        assert_eq!(saved, resaved);
+    }
+
+    #[test]
+    fn face_indices_are_unsigned() {
+        let mut file = sample_file(1, 1, 2.0);
+        file.model.face_indices.0[0] = 255;
+        let mut saved = Vec::new();
+        file.save(&mut saved).unwrap();
+        let (loaded, warnings) = PmxFile::load_with(&mut &saved[..], LoadMode::Normal).unwrap();
+        assert_eq!(loaded.model.face_indices.0[0], 255);
+        assert_eq!(warnings[0].kind, WarningKind::IndexOutOfRange { target: "vertices", index: 255 });
+    }
+
+    #[test]
+    fn save_sets_bone_flags_from_extra() {
+        use self::BoneFlags::*;
+        let mut file = sample_file(1, 2, 2.0);
+        file.model.bones.0[1].flags = ModeSet::new(CanRotate | AxesFixed);
+        file.model.bones.0[2].extra.ik = None;
+        let mut saved = Vec::new();
+        file.save(&mut saved).unwrap();
+
+        let loaded = PmxFile::from_bytes(&saved).unwrap();
+        let bones = &loaded.model.bones.0;
+        assert_eq!(bones[1].flags.bits(), (TargetMode | CanRotate | AppendRotate | LocalAxes).bits());
+        assert_eq!(bones[2].flags.bits(), (CanRotate | AxesFixed | DeformOuterParent).bits());
+        match bones[1].extra.append {
+            Some(AppendTransform { ref parent, ratio, rotate: true, translate: false, local: false }) => assert_eq!((parent.0, ratio), (0, -0.5)),
+            ref other => panic!("unexpected append {:?}", other),
+        }
+    }
+
+    #[test]
+    fn save_rejects_out_of_range_index() {
+        let mut file = sample_file(1, 1, 2.0);
+        file.model.face_indices.0[0] = 300;
+        let e = file.save(&mut Vec::new()).unwrap_err();
+        match *e.kind() {
+            ErrorKind::IndexOutOfRange(300) => {}
+            ref other => panic!("unexpected error {:?}", other),
+        }
+        assert_eq!(e.section(), Some("faces"));
+    }
+
+    #[test]
+    fn load_reports_section_and_offset() {
+        let e = PmxFile::load(&mut Cursor::new(b"PMD \0\0\0\0")).unwrap_err();
+        match *e.kind() {
+            ErrorKind::BadMagic => {}
+            ref other => panic!("unexpected error {:?}", other),
+        }
+        assert_eq!((e.section(), e.offset()), (Some("header"), 4));
+
+        let mut saved = Vec::new();
+        sample_file(1, 2, 2.0).save(&mut saved).unwrap();
+        let len = saved.len() - 3;
+        saved.truncate(len);
+        let e = PmxFile::load(&mut Cursor::new(&saved)).unwrap_err();
+        match *e.kind() {
+            ErrorKind::Io(ref e) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            ref other => panic!("unexpected error {:?}", other),
+        }
+        assert_eq!((e.section(), e.offset()), (Some("joints"), len as u64));
+    }
+
+    #[test]
+    fn load_truncated_files() {
+        for &(encode, index_size, version) in &[(0, 1, 2.0), (1, 4, 2.1)] {
+            let mut saved = Vec::new();
+            sample_file(encode, index_size, version).save(&mut saved).unwrap();
+            for len in 0..saved.len() {
+                assert!(PmxFile::load(&mut &saved[..len]).is_err(), "loaded {} of {} bytes", len, saved.len());
+            }
+        }
+    }
+
+    #[test]
+    fn load_garbled_files() {
+        let mut saved = Vec::new();
+        sample_file(0, 2, 2.1).save(&mut saved).unwrap();
+        // xorshift, to keep the corpus reproducible
+        let mut state = 0x2545_f491_u32;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as usize
+        };
+        for _ in 0..2000 {
+            let mut garbled = saved.clone();
+            for _ in 0..1 + next() % 8 {
+                let i = next() % garbled.len();
+                garbled[i] = next() as u8;
+            }
+            // Only panics and aborts fail the test
+            let _ = PmxFile::load(&mut &garbled[..]);
+        }
+    }
+
+    #[test]
+    fn load_rejects_huge_counts() {
+        let mut header = b"PMX ".to_vec();
+        header.write_f32::<LE>(2.0).unwrap();
+        header.extend_from_slice(&[8, 1, 0, 1, 1, 1, 1, 1, 1]);
+
+        let mut data = header.clone();
+        data.write_u32::<LE>(u32::max_value()).unwrap();
+        data.extend_from_slice(b"abc");
+        let e = PmxFile::load(&mut &data[..]).unwrap_err();
+        assert_eq!(e.section(), Some("model name"));
+
+        let mut data = header;
+        data.extend_from_slice(&[0; 16]);
+        data.write_u32::<LE>(u32::max_value()).unwrap();
+        data.extend_from_slice(&[0; 64]);
+        let e = PmxFile::load(&mut &data[..]).unwrap_err();
+        assert_eq!(e.section(), Some("vertices"));
+    }
+
+    #[test]
+    fn load_rejects_odd_utf16_length() {
+        let mut data = b"PMX ".to_vec();
+        data.write_f32::<LE>(2.0).unwrap();
+        data.extend_from_slice(&[8, 0, 0, 1, 1, 1, 1, 1, 1]);
+        data.write_u32::<LE>(3).unwrap();
+        data.extend_from_slice(&[0x42, 0x30, 0x42]);
+        match *PmxFile::load(&mut &data[..]).unwrap_err().kind() {
+            ErrorKind::InvalidString => {}
+            ref other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+    #[test]
+    fn load_warns_about_invalid_utf16() {
+        let mut saved = Vec::new();
+        sample_file(0, 2, 2.0).save(&mut saved).unwrap();
+        // an unpaired surrogate in place of the first character of the model name
+        let name_at = 4 + 4 + 9 + 4;
+        saved[name_at..name_at + 2].copy_from_slice(&[0x00, 0xd8]);
+
+        let (pmx, warnings) = PmxFile::load_with(&mut &saved[..], LoadMode::Normal).unwrap();
+        assert_eq!(pmx.model_name.jp.0, "\u{fffd}ンプル");
+        assert_eq!(
+            warnings,
+            vec![Warning { section: "model name", index: None, kind: WarningKind::InvalidString("\u{fffd}ンプル".to_owned()) }]
+        );
+
+        let e = PmxFile::load_with(&mut &saved[..], LoadMode::Strict).unwrap_err();
+        match *e.kind() {
+            ErrorKind::Strict(ref w) => assert_eq!(w.section, "model name"),
+            ref other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decode_warns_about_unknown_flags() {
+        let p = helper();
+        let flags = ModeSet::<BoneFlags>::decode::<LE>(&mut Cursor::new(vec![0x02, 0x40]), &p).unwrap();
+        assert_eq!(flags.bits(), 0x0002);
+        assert_eq!(*p.diagnostics.warnings.borrow(), vec![Warning {
+            section: "header",
+            index: None,
+            kind: WarningKind::UnknownFlags { ty: "BoneFlags", bits: 0x4000 },
+        }]);
+    }
+
+    #[test]
+    fn save_keeps_unknown_flags() {
+        let mut file = sample_file(1, 2, 2.0);
+        file.model.bones.0[0].flags.1 = 0x4000;
+        let mut saved = Vec::new();
+        file.save(&mut saved).unwrap();
+
+        let (loaded, warnings) = PmxFile::load_with(&mut &saved[..], LoadMode::Normal).unwrap();
+        assert_eq!(warnings[0].kind, WarningKind::UnknownFlags { ty: "BoneFlags", bits: 0x4000 });
+        assert_eq!(loaded.model.bones.0[0].flags.1, 0x4000);
+        let mut resaved = Vec::new();
+        loaded.save(&mut resaved).unwrap();
+        assert_eq!(saved, resaved);
+    }
+
+    #[test]
+    fn save_drops_empty_append() {
+        let mut file = sample_file(1, 2, 2.0);
+        {
+            let bones = &mut file.model.bones.0;
+            bones[1].extra.append = Some(AppendTransform { parent: Index(0), ratio: 0.5, rotate: false, translate: false, local: true });
+            bones[2].flags.0.insert(BoneFlags::AppendLocal);
+            bones[2].extra.append = None;
+        }
+        let mut saved = Vec::new();
+        file.save(&mut saved).unwrap();
+
+        let loaded = PmxFile::from_bytes(&saved).unwrap();
+        {
+            let bones = &loaded.model.bones.0;
+            assert!(bones[1].extra.append.is_none());
+            assert!(!bones[1].flags.0.contains(BoneFlags::AppendLocal));
+            assert!(bones[2].extra.append.is_none());
+            assert!(bones[2].flags.0.contains(BoneFlags::AppendLocal));
+            assert_eq!(bones[2].name.0, "足ＩＫ");
+        }
+        let mut resaved = Vec::new();
+        loaded.save(&mut resaved).unwrap();
+        assert_eq!(saved, resaved);
+    }
+
+    #[test]
+    fn load_warns_about_references_and_weights() {
+        let mut file = sample_file(1, 2, 2.0);
+        file.model.face_indices.0[4] = 5;
+        file.model.vertices.0[2].bone_weight = BoneWeight::BDEF4 { indices: [0, 1, 2, 3], weights: [0.5, 0.5, 0.5, 0.0] };
+        let mut saved = Vec::new();
+        file.save(&mut saved).unwrap();
+
+        let (_, warnings) = PmxFile::load_with(&mut &saved[..], LoadMode::Normal).unwrap();
+        assert_eq!(
+            warnings,
+            vec![
+                Warning { section: "vertices", index: Some(2), kind: WarningKind::WeightSum(1.5) },
+                Warning { section: "vertices", index: Some(2), kind: WarningKind::IndexOutOfRange { target: "bones", index: 3 } },
+                Warning { section: "faces", index: Some(4), kind: WarningKind::IndexOutOfRange { target: "vertices", index: 5 } },
+            ]
+        );
+        let e = PmxFile::load_with(&mut &saved[..], LoadMode::Strict).unwrap_err();
+        assert_eq!(e.section(), Some("vertices"));
+    }
+
+    #[test]
+    fn load_truncated_file_leniently() {
+        let file = sample_file(1, 2, 2.1);
+        let mut saved = Vec::new();
+        file.save(&mut saved).unwrap();
+
+        // everything before the morphs is the same without them
+        let mut head = sample_file(1, 2, 2.1);
+        head.model = Model { morphs: Array::default(), display_frames: Array::default(), ..head.model };
+        head.model.rigid_bodies.0.clear();
+        head.model.joints.0.clear();
+        head.model.soft_bodies.0.clear();
+        let mut morphs_at = Vec::new();
+        head.save(&mut morphs_at).unwrap();
+        let morphs_at = morphs_at.len() - 5 * 4;
+
+        let mut morph = Vec::new();
+        file.model.morphs.0[0].encode::<LE>(&mut morph, &PmxEncodeHelper::from_header(&file.header).unwrap()).unwrap();
+        // cut in the middle of the second morph
+        saved.truncate(morphs_at + 4 + morph.len() + 3);
+
+        assert!(PmxFile::load(&mut &saved[..]).is_err());
+        let (pmx, warnings) = PmxFile::load_with(&mut &saved[..], LoadMode::Lenient).unwrap();
+        assert_eq!(pmx.model.bones.0.len(), 3);
+        assert_eq!(pmx.model.morphs.0.len(), 1);
+        assert_eq!(pmx.missing_sections(), &["morphs", "display frames", "rigid bodies", "joints", "soft bodies"]);
+        match warnings[..] {
+            [Warning { section: "morphs", kind: WarningKind::Truncated(_), .. }] => {}
+            ref other => panic!("unexpected warnings {:?}", other),
+        }
+
+        let (pmx, _) = PmxFile::load_with(&mut &saved[..17], LoadMode::Lenient).unwrap();
+        assert_eq!(pmx.missing_sections(), &SECTIONS[..]);
+        assert!(PmxFile::load_with(&mut &saved[..16], LoadMode::Lenient).is_err());
+    }
+
+    #[test]
+    fn probe_counts() {
+        for &(encode, index_size, version) in &[(0, 1, 2.0), (1, 2, 2.0), (0, 4, 2.1)] {
+            let mut saved = Vec::new();
+            sample_file(encode, index_size, version).save(&mut saved).unwrap();
+            let probe = PmxFile::probe(&mut Cursor::new(&saved)).unwrap();
+            assert_eq!(probe.version, version);
+            assert_eq!(probe.encoding, if encode == 0 { TextEncoding::Utf16 } else { TextEncoding::Utf8 });
+            assert_eq!(probe.model_name.jp.0, "サンプル");
+            assert_eq!(probe.comment.en.0, "Comment");
+            assert_eq!(
+                [probe.num_vertices, probe.num_face_indices, probe.num_textures, probe.num_materials, probe.num_bones, probe.num_morphs],
+                [5, 6, 1, 1, 3, 7]
+            );
+        }
+    }
+}
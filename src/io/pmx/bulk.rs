@@ -0,0 +1,255 @@
+//! Decoding of the vertex and face arrays straight from the read buffer.
+//! Elements which lie wholly in the buffer are decoded from the slice,
+//! so each one costs a few loads instead of a `read_exact` per field.
+
+use super::super::err;
+use super::super::newtypes::*;
+use super::{BoneWeight, Vertex};
+use error::ErrorKind;
+
+use std::io::{self, BufRead, Result};
+
+use byteorder::{ByteOrder, LE};
+use cgmath::{Vector2, Vector3, Vector4};
+use pod_io::{Decode, Nil};
+
+/// Large enough that refilling it is rare next to decoding it
+pub(super) const CHUNK: usize = 1 << 16;
+
+/// The number of bone indices and the bytes of weights after them
+pub(super) fn weight_layout(ty: u8) -> Result<(usize, usize)> {
+    match ty {
+        0 => Ok((1, 0)),
+        1 => Ok((2, 4)),
+        2 | 4 => Ok((4, 16)),
+        3 => Ok((2, 4 + 36)),
+        _ => Err(err(ErrorKind::InvalidBoneWeightType(ty))),
+    }
+}
+
+/// Vertex indices of 1 and 2 bytes are unsigned
+pub(super) fn read_vertex_index(b: &[u8], size: usize) -> u32 {
+    match size {
+        1 => u32::from(b[0]),
+        2 => u32::from(LE::read_u16(b)),
+        _ => LE::read_u32(b),
+    }
+}
+
+/// Other indices as `PmxHelper::read_index` reads them, with -1 for none
+pub(super) fn read_index(b: &[u8], size: usize) -> i32 {
+    match size {
+        1 if b[0] == 0xFF => -1,
+        1 => i32::from(b[0]),
+        2 if LE::read_u16(b) == 0xFFFF => -1,
+        2 => i32::from(LE::read_u16(b)),
+        _ if LE::read_i32(b) == i32::max_value() => -1,
+        _ => LE::read_i32(b),
+    }
+}
+
+pub(super) fn read_vec2(b: &[u8]) -> Vector2<f32> {
+    Vector2::new(LE::read_f32(b), LE::read_f32(&b[4..]))
+}
+
+pub(super) fn read_vec3(b: &[u8]) -> Vector3<f32> {
+    Vector3::new(LE::read_f32(b), LE::read_f32(&b[4..]), LE::read_f32(&b[8..]))
+}
+
+pub(super) fn read_vec4(b: &[u8]) -> Vector4<f32> {
+    let mut v = [0.0; 4];
+    LE::read_f32_into(&b[..16], &mut v);
+    Vector4::from(v)
+}
+
+/// Appends the indices in `b`, which holds a whole number of them
+pub(super) fn extend_vertex_indices(out: &mut Vec<u32>, b: &[u8], size: usize) {
+    match size {
+        1 => out.extend(b.iter().map(|&i| u32::from(i))),
+        2 => out.extend(b.chunks_exact(2).map(|i| u32::from(LE::read_u16(i)))),
+        _ => out.extend(b.chunks_exact(4).map(LE::read_u32)),
+    }
+}
+
+/// Appends a counted array of vertex indices,
+/// and keeps the ones decoded before an error
+pub(super) fn read_faces<R: BufRead>(r: &mut R, size: usize, out: &mut Vec<u32>) -> Result<()> {
+    let mut left = u32::decode::<LE>(r, Nil)? as usize;
+    out.reserve(prealloc::<u32>(left));
+    while left > 0 {
+        let n = {
+            let buf = r.fill_buf()?;
+            let n = (buf.len() / size).min(left);
+            extend_vertex_indices(out, &buf[..n * size], size);
+            n
+        };
+        if n == 0 {
+            // An index split by the end of the buffer, or the end of the input
+            let mut b = [0; 4];
+            r.read_exact(&mut b[..size])?;
+            out.push(read_vertex_index(&b, size));
+            left -= 1;
+        } else {
+            r.consume(n * size);
+            left -= n;
+        }
+    }
+    Ok(())
+}
+
+/// Decodes vertices of the layout given by the header
+pub(super) struct VertexDecoder {
+    pub(super) additional: usize,
+    pub(super) bone_index_size: usize,
+}
+
+impl VertexDecoder {
+    /// The bytes before the bone weight type
+    fn fixed(&self) -> usize {
+        32 + 16 * self.additional
+    }
+
+    fn size_of_type(&self, ty: u8) -> Result<usize> {
+        let (bones, weights) = weight_layout(ty)?;
+        Ok(self.fixed() + 1 + bones * self.bone_index_size + weights + 4)
+    }
+
+    /// The size of the vertex at the start of `b`, or `None` if `b` is too short to hold it
+    pub(super) fn size(&self, b: &[u8]) -> Result<Option<usize>> {
+        let fixed = self.fixed();
+        let ty = match b.get(fixed) {
+            Some(&ty) => ty,
+            None => return Ok(None),
+        };
+        let size = self.size_of_type(ty)?;
+        Ok(if size <= b.len() { Some(size) } else { None })
+    }
+
+    /// `b` holds exactly one vertex, as measured by `size`
+    pub(super) fn decode(&self, b: &[u8]) -> Vertex {
+        let fixed = self.fixed();
+        let additional = (0..self.additional).map(|i| Vec4(read_vec4(&b[32 + 16 * i..]))).collect();
+        Vertex {
+            position: Vec3(read_vec3(b)),
+            normal: Vec3(read_vec3(&b[12..])),
+            uv: Vec2(read_vec2(&b[24..])),
+            additional: Array(additional),
+            bone_weight: self.decode_bone_weight(b[fixed], &b[fixed + 1..]),
+            edge_scale: LE::read_f32(&b[b.len() - 4..]),
+        }
+    }
+
+    fn decode_bone_weight(&self, ty: u8, b: &[u8]) -> BoneWeight {
+        use self::BoneWeight::{BDEF1, BDEF2, BDEF4, QDEF, SDEF};
+        let size = self.bone_index_size;
+        let index = |i: usize| read_index(&b[i * size..], size);
+        match ty {
+            0 => BDEF1 { index: index(0) },
+            1 => BDEF2 { indices: [index(0), index(1)], weight: LE::read_f32(&b[2 * size..]) },
+            3 => {
+                let v = &b[2 * size + 4..];
+                SDEF {
+                    indices: [index(0), index(1)],
+                    weight: LE::read_f32(&b[2 * size..]),
+                    c: Vec3(read_vec3(v)),
+                    r0: Vec3(read_vec3(&v[12..])),
+                    r1: Vec3(read_vec3(&v[24..])),
+                }
+            }
+            _ => {
+                let indices = [index(0), index(1), index(2), index(3)];
+                let mut weights = [0.0; 4];
+                LE::read_f32_into(&b[4 * size..4 * size + 16], &mut weights);
+                if ty == 2 {
+                    BDEF4 { indices, weights }
+                } else {
+                    QDEF { indices, weights }
+                }
+            }
+        }
+    }
+
+    /// Appends a counted array of vertices,
+    /// and keeps the ones decoded before an error
+    pub(super) fn read_vertices<R: BufRead>(&self, r: &mut R, out: &mut Vec<Vertex>) -> Result<()> {
+        let mut left = u32::decode::<LE>(r, Nil)? as usize;
+        out.reserve(prealloc::<Vertex>(left));
+        let mut split = Vec::new();
+        while left > 0 {
+            let (used, result) = {
+                let buf = r.fill_buf()?;
+                let mut used = 0;
+                let mut result = Ok(());
+                while left > 0 {
+                    match self.size(&buf[used..]) {
+                        Ok(Some(size)) => {
+                            out.push(self.decode(&buf[used..used + size]));
+                            used += size;
+                            left -= 1;
+                        }
+                        Ok(None) => break,
+                        Err(e) => {
+                            result = Err(e);
+                            break;
+                        }
+                    }
+                }
+                (used, result)
+            };
+            // Consumed first, so that errors point at the vertex
+            r.consume(used);
+            result?;
+            if used == 0 && left > 0 {
+                // A vertex split by the end of the buffer, or the end of the input
+                split.resize(self.fixed() + 1, 0);
+                r.read_exact(&mut split)?;
+                let size = self.size_of_type(split[self.fixed()])?;
+                split.resize(size, 0);
+                r.read_exact(&mut split[self.fixed() + 1..])?;
+                out.push(self.decode(&split));
+                left -= 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes `faces` with the vertex index size of the header
+pub(super) fn write_faces<W: io::Write>(w: &mut W, size: usize, faces: &[u32]) -> Result<()> {
+    let max = match size {
+        1 => 0xFF,
+        2 => 0xFFFF,
+        _ => i32::max_value() as u32,
+    };
+    (faces.len() as u32).encode::<LE>(w, Nil)?;
+    let mut buf = Vec::with_capacity(CHUNK);
+    for chunk in faces.chunks(CHUNK / 4) {
+        buf.clear();
+        for &i in chunk {
+            if i > max {
+                return Err(err(ErrorKind::IndexOutOfRange(i as i32)));
+            }
+            match size {
+                1 => buf.push(i as u8),
+                2 => buf.extend_from_slice(&(i as u16).to_le_bytes()),
+                _ => buf.extend_from_slice(&i.to_le_bytes()),
+            }
+        }
+        w.write_all(&buf)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_index_maps_max_to_none() {
+        assert_eq!(read_index(&[0xFF], 1), -1);
+        assert_eq!(read_index(&[0xFF, 0xFF], 2), -1);
+        assert_eq!(read_index(&[0xFF, 0xFF, 0xFF, 0x7F], 4), -1);
+        assert_eq!(read_index(&[0xFE, 0xFF, 0xFF, 0x7F], 4), i32::max_value() - 1);
+        assert_eq!(read_vertex_index(&[0xFF, 0xFF], 2), 0xFFFF);
+    }
+}
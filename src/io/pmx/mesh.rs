@@ -0,0 +1,151 @@
+//! The faces of a model split by material, as draw calls need them.
+//!
+//! ```no_run
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use mmd::io::pmx::PmxFile;
+//!
+//! let pmx = PmxFile::from_file("model.pmx")?;
+//! for (material, faces) in pmx.model.submeshes()? {
+//!     println!("{}: {} triangles", material.name.jp.0, faces.len() / 3);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use super::validate::{Finding, FindingKind, Severity};
+use super::{Material, Model};
+use error::WarningKind;
+
+use std::slice;
+
+/// The materials of a model with the face indices drawn with each one
+#[derive(Debug, Clone)]
+pub struct SubMeshes<'a> {
+    materials: slice::Iter<'a, Material>,
+    faces: &'a [u32],
+}
+
+impl<'a> Iterator for SubMeshes<'a> {
+    type Item = (&'a Material, &'a [u32]);
+
+    fn next(&mut self) -> Option<(&'a Material, &'a [u32])> {
+        let material = self.materials.next()?;
+        // The counts were checked by `Model::submeshes`
+        let (faces, rest) = self.faces.split_at(material.num_vertex_indices as usize);
+        self.faces = rest;
+        Some((material, faces))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.materials.size_hint()
+    }
+}
+
+impl<'a> ExactSizeIterator for SubMeshes<'a> {}
+
+/// A sub-mesh with its own vertex list
+#[derive(Debug, Clone)]
+pub struct CompactSubMesh<'a> {
+    pub material: &'a Material,
+    /// Indices into `Model::vertices`, in the order the faces first use them
+    pub vertices: Vec<u32>,
+    /// Three per triangle, indexing `vertices`
+    pub faces: Vec<u32>,
+}
+
+impl Model {
+    /// The face indices of each material, in order.
+    /// Fails if a material has a negative count or one which is not a multiple of 3,
+    /// or if the counts do not add up to the number of face indices.
+    pub fn submeshes<'a>(&'a self) -> Result<SubMeshes<'a>, Finding> {
+        let error = |index, kind| Finding { severity: Severity::Error, section: "materials", index, kind };
+        let faces = self.face_indices.0.len();
+        let mut total = 0;
+        for (i, m) in self.materials.0.iter().enumerate() {
+            if m.num_vertex_indices < 0 || m.num_vertex_indices % 3 != 0 {
+                return Err(error(Some(i), FindingKind::InvalidFaceCount(m.num_vertex_indices)));
+            }
+            total += i64::from(m.num_vertex_indices);
+        }
+        if total != faces as i64 {
+            return Err(error(None, FindingKind::FaceCountMismatch { materials: total, faces }));
+        }
+        Ok(SubMeshes { materials: self.materials.0.iter(), faces: &self.face_indices.0 })
+    }
+
+    /// `submeshes` with only the vertices used by each material.
+    /// Also fails on a face index out of range of the vertices.
+    pub fn compact_submeshes<'a>(&'a self) -> Result<Vec<CompactSubMesh<'a>>, Finding> {
+        const UNUSED: u32 = !0;
+        let submeshes = self.submeshes()?;
+        let vertices = self.vertices.0.len();
+        if let Some(i) = self.face_indices.0.iter().position(|&v| v as usize >= vertices) {
+            let kind = WarningKind::IndexOutOfRange { target: "vertices", index: self.face_indices.0[i] as i32 };
+            return Err(Finding { severity: Severity::Error, section: "faces", index: Some(i), kind: FindingKind::Load(kind) });
+        }
+
+        // The new index of each vertex, reset after each sub-mesh
+        let mut remap = vec![UNUSED; vertices];
+        Ok(submeshes
+            .map(|(material, faces)| {
+                let mut used = Vec::new();
+                let faces = faces
+                    .iter()
+                    .map(|&v| {
+                        let new = &mut remap[v as usize];
+                        if *new == UNUSED {
+                            *new = used.len() as u32;
+                            used.push(v);
+                        }
+                        *new
+                    })
+                    .collect();
+                for &v in &used {
+                    remap[v as usize] = UNUSED;
+                }
+                CompactSubMesh { material, vertices: used, faces }
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::sample_file;
+    use super::*;
+
+    #[test]
+    fn submeshes_split_faces() {
+        let mut file = sample_file(1, 2, 2.0);
+        let model = &mut file.model;
+        let mut second = sample_file(1, 2, 2.0).model.materials.0.pop().unwrap();
+        second.num_vertex_indices = 3;
+        model.materials.0[0].num_vertex_indices = 3;
+        model.materials.0.push(second);
+
+        let submeshes = model.submeshes().unwrap().map(|(_, faces)| faces).collect::<Vec<_>>();
+        assert_eq!(submeshes, vec![&[0, 1, 2][..], &[2, 3, 4][..]]);
+
+        let compact = model.compact_submeshes().unwrap();
+        assert_eq!(compact[1].vertices, vec![2, 3, 4]);
+        assert_eq!(compact[1].faces, vec![0, 1, 2]);
+        assert_eq!(compact[0].vertices, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn submeshes_reject_bad_counts() {
+        let mut file = sample_file(1, 2, 2.0);
+        let model = &mut file.model;
+        model.materials.0[0].num_vertex_indices = 3;
+        let e = model.submeshes().unwrap_err();
+        assert_eq!(e.kind, FindingKind::FaceCountMismatch { materials: 3, faces: 6 });
+
+        model.materials.0[0].num_vertex_indices = -6;
+        assert_eq!(model.submeshes().unwrap_err().index, Some(0));
+
+        model.materials.0[0].num_vertex_indices = 6;
+        model.face_indices.0[5] = 5;
+        let e = model.compact_submeshes().unwrap_err();
+        assert_eq!((e.section, e.index), ("faces", Some(5)));
+    }
+}
@@ -0,0 +1,187 @@
+//! Loading from memory with the vertices, faces and morphs decoded on the
+//! rayon thread pool. The other sections are small, so they are decoded while
+//! finding where the large ones start.
+
+use super::super::newtypes::*;
+use super::super::{Load, Tracker};
+use super::bulk::{self, VertexDecoder, CHUNK};
+use super::{read_header, Header, Model, Morph, MorphType, Name, PmxFile, PmxHelper, Vertex};
+use error;
+
+use std::io::{self, Result};
+
+use byteorder::LE;
+use pod_io::{Decode, Nil};
+use rayon::prelude::*;
+
+/// Morphs decoded by one task
+const MORPHS_PER_TASK: usize = 64;
+
+impl PmxFile {
+    /// Any error is reported by loading again with the sequential loader,
+    /// so the result is always the same as `from_bytes`.
+    pub(crate) fn load_parallel(bytes: &[u8]) -> error::Result<PmxFile> {
+        load(bytes).or_else(|_| PmxFile::load_bytes(bytes))
+    }
+}
+
+fn load(bytes: &[u8]) -> error::Result<PmxFile> {
+    let r = &mut Tracker::new(bytes);
+    let (magic, header, p) = read_header(r, false)?;
+    let model_name = p.section(r, "model name", |r| Name::decode::<LE>(r, &p))?;
+    let comment = p.section(r, "comment", |r| Name::decode::<LE>(r, &p))?;
+
+    let vertices = VertexDecoder { additional: p.additional, bone_index_size: p.bone_index_size };
+    let vertex_runs = p.section(r, "vertices", |r| split_vertices(r, &vertices))?;
+    let faces = p.section(r, "faces", |r| {
+        let n = u32::decode::<LE>(r, Nil)? as usize;
+        r.borrow(n.saturating_mul(p.vertex_index_size))
+    })?;
+    let mut model = Model::default();
+    p.section(r, "textures", |r| model.textures.decode_into(r, &p))?;
+    p.section(r, "materials", |r| model.materials.decode_into(r, &p))?;
+    p.section(r, "bones", |r| model.bones.decode_into(r, &p))?;
+    let morphs = p.section(r, "morphs", |r| split_morphs(r, &header))?;
+    p.section(r, "display frames", |r| model.display_frames.decode_into(r, &p))?;
+    p.section(r, "rigid bodies", |r| model.rigid_bodies.decode_into(r, &p))?;
+    p.section(r, "joints", |r| model.joints.decode_into(r, &p))?;
+    if p.version >= 2.1 {
+        p.section(r, "soft bodies", |r| model.soft_bodies.decode_into(r, &p))?;
+    }
+
+    // `p` is not `Sync`
+    let vertex_index_size = p.vertex_index_size;
+    let (v, (f, m)) = rayon::join(
+        || decode_vertices(&vertices, &vertex_runs),
+        || rayon::join(|| decode_faces(faces, vertex_index_size), || decode_morphs(&header, &morphs)),
+    );
+    model.vertices = Array(v);
+    model.face_indices = Array(f);
+    model.morphs = Array(r.section("morphs", |_| m)?);
+
+    Ok(PmxFile { magic, header, model_name, comment, model, missing: Vec::new() })
+}
+
+/// Splits a counted array of vertices into runs of about `CHUNK` bytes
+fn split_vertices<'a>(r: &mut Tracker<&'a [u8]>, vertices: &VertexDecoder) -> Result<Vec<&'a [u8]>> {
+    let n = u32::decode::<LE>(r, Nil)?;
+    let mut runs = Vec::new();
+    let mut run = r.inner;
+    for _ in 0..n {
+        let size = match vertices.size(r.inner)? {
+            Some(size) => size,
+            None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer")),
+        };
+        r.borrow(size)?;
+        let len = run.len() - r.inner.len();
+        if len >= CHUNK {
+            runs.push(&run[..len]);
+            run = r.inner;
+        }
+    }
+    runs.push(&run[..run.len() - r.inner.len()]);
+    Ok(runs)
+}
+
+fn decode_vertices(vertices: &VertexDecoder, runs: &[&[u8]]) -> Vec<Vertex> {
+    let decoded = runs
+        .par_iter()
+        .map(|&run| {
+            let mut out = Vec::new();
+            let mut b = run;
+            while let Ok(Some(size)) = vertices.size(b) {
+                out.push(vertices.decode(&b[..size]));
+                b = &b[size..];
+            }
+            out
+        })
+        .collect::<Vec<_>>();
+    decoded.into_iter().flatten().collect()
+}
+
+fn decode_faces(faces: &[u8], size: usize) -> Vec<u32> {
+    let decoded = faces
+        .par_chunks(CHUNK)
+        .map(|b| {
+            let mut out = Vec::with_capacity(b.len() / size);
+            bulk::extend_vertex_indices(&mut out, b, size);
+            out
+        })
+        .collect::<Vec<_>>();
+    decoded.concat()
+}
+
+/// Splits a counted array of morphs into one slice per morph
+fn split_morphs<'a>(r: &mut Tracker<&'a [u8]>, h: &Header) -> Result<Vec<&'a [u8]>> {
+    let n = u32::decode::<LE>(r, Nil)? as usize;
+    let mut morphs = Vec::with_capacity(prealloc::<&[u8]>(n));
+    for _ in 0..n {
+        let start = r.inner;
+        for _ in 0..2 {
+            let len = u32::decode::<LE>(r, Nil)?;
+            r.borrow(len as usize)?;
+        }
+        // panel
+        r.borrow(1)?;
+        let size = match MorphType::decode::<LE>(r, Nil)? {
+            MorphType::Group | MorphType::Flip => h.morph_index_size as usize + 4,
+            MorphType::Position => h.vertex_index_size as usize + 12,
+            MorphType::Bone => h.bone_index_size as usize + 28,
+            MorphType::UV | MorphType::AddUV1 | MorphType::AddUV2 | MorphType::AddUV3 | MorphType::AddUV4 => {
+                h.vertex_index_size as usize + 16
+            }
+            MorphType::Material => h.material_index_size as usize + 113,
+            MorphType::Impulse => h.rigidbody_index_size as usize + 25,
+        };
+        let count = u32::decode::<LE>(r, Nil)? as usize;
+        r.borrow(count.saturating_mul(size))?;
+        morphs.push(&start[..start.len() - r.inner.len()]);
+    }
+    Ok(morphs)
+}
+
+/// The helpers are not `Sync`, so each task has its own
+fn decode_morphs(h: &Header, morphs: &[&[u8]]) -> Result<Vec<Morph>> {
+    let decoded = morphs
+        .par_chunks(MORPHS_PER_TASK)
+        .map(|chunk| {
+            let p = PmxHelper::from_header(h, false)?;
+            chunk.iter().map(|&m| Morph::decode::<LE>(&mut &m[..], &p)).collect::<Result<Vec<_>>>()
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(decoded.into_iter().flatten().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{sample_file, v3, vertex};
+    use super::super::BoneWeight;
+    use super::*;
+
+    #[test]
+    fn parallel_matches_sequential() {
+        for &(encode, index_size, version) in &[(0, 1, 2.0), (1, 2, 2.0), (0, 4, 2.1), (1, 1, 2.1)] {
+            let mut saved = Vec::new();
+            sample_file(encode, index_size, version).save(&mut saved).unwrap();
+            let sequential = PmxFile::from_bytes(&saved).unwrap();
+            let parallel = PmxFile::load_parallel(&saved).unwrap();
+            assert_eq!(format!("{:?}", parallel), format!("{:?}", sequential));
+
+            let truncated = &saved[..saved.len() - 3];
+            let e = PmxFile::load_parallel(truncated).unwrap_err();
+            let expected = PmxFile::from_bytes(truncated).unwrap_err();
+            assert_eq!((e.section(), e.offset()), (expected.section(), expected.offset()));
+        }
+    }
+
+    #[test]
+    fn parallel_splits_large_arrays() {
+        let mut file = sample_file(0, 4, 2.0);
+        file.model.vertices = Array((0..5000).map(|i| vertex(v3(i as f32, 0.0, 0.0), BoneWeight::BDEF1 { index: i % 3 }, 1)).collect());
+        file.model.face_indices = Array((0..60000).map(|i| i % 5000).collect());
+        let mut saved = Vec::new();
+        file.save(&mut saved).unwrap();
+        let parallel = PmxFile::load_parallel(&saved).unwrap();
+        assert_eq!(format!("{:?}", parallel), format!("{:?}", PmxFile::from_bytes(&saved).unwrap()));
+    }
+}
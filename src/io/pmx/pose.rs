@@ -0,0 +1,232 @@
+//! The transforms of the bones for one frame, and the matrices to draw it with.
+//!
+//! ```no_run
+//! # extern crate cgmath;
+//! # extern crate mmd;
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use cgmath::{Deg, Quaternion, Rotation3};
+//! use mmd::io::pmx::pose::Pose;
+//! use mmd::io::pmx::PmxFile;
+//!
+//! let pmx = PmxFile::from_file("model.pmx")?;
+//! let skeleton = pmx.model.skeleton()?;
+//! let mut pose = Pose::rest(&skeleton);
+//! if let Some(i) = skeleton.find("頭") {
+//!     pose.bones[i].rotation = Quaternion::from_angle_y(Deg(30.0));
+//! }
+//! let matrices = pose.evaluate(&skeleton);
+//! // A vertex of BDEF1 bone `j` is drawn at `matrices.skinning[j] * position`
+//! # Ok(())
+//! # }
+//! ```
+
+use super::skeleton::Skeleton;
+
+use cgmath::prelude::*;
+use cgmath::{Matrix4, Quaternion, Vector3};
+
+/// The transform of a bone relative to its rest pose, in its parent's space
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoneTransform {
+    pub translation: Vector3<f32>,
+    pub rotation: Quaternion<f32>,
+}
+
+impl Default for BoneTransform {
+    fn default() -> BoneTransform {
+        BoneTransform { translation: Vector3::zero(), rotation: Quaternion::one() }
+    }
+}
+
+/// A `BoneTransform` for each bone of a skeleton, by index
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pose {
+    pub bones: Vec<BoneTransform>,
+}
+
+/// The result of `Pose::evaluate`, by bone index
+#[derive(Debug, Clone, PartialEq)]
+pub struct BoneMatrices {
+    /// From the space of the bone to model space
+    pub world: Vec<Matrix4<f32>>,
+    /// From the rest pose to the posed model, i.e. `world` times the inverse
+    /// of the bind matrix, which moves `Bone::position` to the origin
+    pub skinning: Vec<Matrix4<f32>>,
+}
+
+impl Pose {
+    /// Every bone at its rest position, which gives identity skinning matrices
+    pub fn rest(skeleton: &Skeleton) -> Pose {
+        Pose { bones: vec![BoneTransform::default(); skeleton.len()] }
+    }
+
+    /// Applies the transforms in `Skeleton::evaluation_order`, with the rotation and
+    /// translation which bones inherit by `AppendTransform`.
+    /// A bone whose parent comes later uses the parent's rest transform, as MMD does on
+    /// the first frame. IK and physics are not applied.
+    ///
+    /// Panics if the pose is not of the size of the skeleton.
+    pub fn evaluate(&self, skeleton: &Skeleton) -> BoneMatrices {
+        assert_eq!(self.bones.len(), skeleton.len(), "the pose is for another skeleton");
+        let bones = skeleton.bones();
+        let position = |i: usize| bones[i].position.0;
+        let append = |i: usize| {
+            let a = bones[i].extra.append.as_ref()?;
+            // Out of range parents are reported by `Model::validate`
+            if a.parent.0 >= 0 && (a.parent.0 as usize) < bones.len() && (a.rotate || a.translate) {
+                Some((a, a.parent.0 as usize))
+            } else {
+                None
+            }
+        };
+
+        // What each bone inherited, for the bones which inherit from it
+        let mut append_rotation = vec![Quaternion::one(); bones.len()];
+        let mut append_translation = vec![Vector3::zero(); bones.len()];
+        let mut world = (0..bones.len()).map(|i| Matrix4::from_translation(position(i))).collect::<Vec<_>>();
+        for &i in skeleton.evaluation_order() {
+            let t = &self.bones[i];
+            let mut rotation = t.rotation;
+            let mut translation = t.translation;
+            if let Some((a, p)) = append(i) {
+                // A bone which inherits from another one passes on only what it inherited,
+                // unless the transform is local
+                let chained = !a.local && append(p).is_some();
+                if a.rotate {
+                    let r = if chained { append_rotation[p] } else { self.bones[p].rotation };
+                    append_rotation[i] = pow(r, a.ratio);
+                    // The inherited rotation is applied first, as in MMD
+                    rotation = rotation * append_rotation[i];
+                }
+                if a.translate {
+                    let v = if chained { append_translation[p] } else { self.bones[p].translation };
+                    append_translation[i] = v * a.ratio;
+                    translation += append_translation[i];
+                }
+            }
+
+            let offset = match skeleton.parent(i) {
+                Some(p) => position(i) - position(p),
+                None => position(i),
+            };
+            let local = Matrix4::from_translation(offset + translation) * Matrix4::from(rotation);
+            world[i] = match skeleton.parent(i) {
+                Some(p) => world[p] * local,
+                None => local,
+            };
+        }
+
+        let skinning = world.iter().enumerate().map(|(i, w)| w * Matrix4::from_translation(-position(i))).collect();
+        BoneMatrices { world, skinning }
+    }
+}
+
+/// The rotation of `q` around the same axis, times `t`.
+/// Unlike slerp from the identity, it is exact for negative `t`.
+fn pow(q: Quaternion<f32>, t: f32) -> Quaternion<f32> {
+    // The shorter way round
+    let q = if q.s < 0.0 { -q } else { q };
+    let sin = q.v.magnitude();
+    if sin < 1e-6 {
+        return Quaternion::one();
+    }
+    let half = sin.atan2(q.s) * t;
+    Quaternion::from_sv(half.cos(), q.v * (half.sin() / sin))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::sample_file;
+    use super::*;
+    use super::super::{AppendTransform, Index};
+    use cgmath::{Deg, Point3};
+
+    fn assert_near(a: Point3<f32>, b: Point3<f32>) {
+        assert!((a - b).magnitude() < 1e-5, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn rest_pose_skins_to_identity() {
+        let file = sample_file(1, 2, 2.0);
+        let skeleton = file.model.skeleton().unwrap();
+        let matrices = Pose::rest(&skeleton).evaluate(&skeleton);
+        assert!(matrices.skinning.iter().all(|m| *m == Matrix4::identity()));
+        assert_eq!(matrices.world[1], Matrix4::from_translation(Vector3::new(0.0, 1.0, 0.0)));
+    }
+
+    #[test]
+    fn pose_moves_children() {
+        let mut file = sample_file(1, 2, 2.0);
+        for bone in &mut file.model.bones.0 {
+            bone.extra.append = None;
+        }
+        let skeleton = file.model.skeleton().unwrap();
+        let mut pose = Pose::rest(&skeleton);
+        pose.bones[0].translation = Vector3::new(0.0, 0.0, 2.0);
+        pose.bones[0].rotation = Quaternion::from_angle_z(Deg(90.0));
+        pose.bones[1].rotation = Quaternion::from_angle_z(Deg(90.0));
+        let matrices = pose.evaluate(&skeleton);
+
+        // Bone 1 rests at (0, 1, 0), so its tip at (0, 2, 0) turns twice
+        let tip = matrices.skinning[1].transform_point(Point3::new(0.0, 2.0, 0.0));
+        assert_near(tip, Point3::new(-1.0, -1.0, 2.0));
+        let origin = matrices.world[2].transform_point(Point3::new(0.0, 0.0, 0.0));
+        assert_near(origin, Point3::new(-1.0, 0.0, 2.0));
+    }
+
+    fn append(parent: i32, ratio: f32, local: bool) -> Option<AppendTransform> {
+        Some(AppendTransform { parent: Index(parent), ratio, rotate: true, translate: true, local })
+    }
+
+    #[test]
+    fn pose_applies_append() {
+        let mut file = sample_file(1, 2, 2.0);
+        {
+            let bones = &mut file.model.bones.0;
+            // Bone 1 turns half as much as bone 0 the other way, and bone 2 inherits that
+            bones[1].extra.append = append(0, -0.5, false);
+            bones[2].extra.append = append(1, 2.0, false);
+        }
+        let skeleton = file.model.skeleton().unwrap();
+        let mut pose = Pose::rest(&skeleton);
+        pose.bones[0].rotation = Quaternion::from_angle_z(Deg(90.0));
+        pose.bones[0].translation = Vector3::new(2.0, 0.0, 0.0);
+        pose.bones[1].rotation = Quaternion::from_angle_z(Deg(90.0));
+        let matrices = pose.evaluate(&skeleton);
+
+        // Bone 1 ends up at 90 + 90 - 45 degrees, and moved by -1 along x in bone 0's space
+        let tip = matrices.skinning[1].transform_point(Point3::new(0.0, 2.0, 0.0));
+        let (sin, cos) = Deg(135.0f32).sin_cos();
+        assert_near(tip, Point3::new(2.0 - 1.0 - sin, -1.0 + cos, 0.0));
+        // Bone 2 inherits the -45 degrees and -1 of bone 1, not its own rotation
+        let tip = matrices.skinning[2].transform_point(Point3::new(0.0, 2.0, 0.0));
+        assert_near(tip, Point3::new(1.0, -1.0, 0.0));
+
+        // A local append inherits the rotation of bone 1 itself, and no translation
+        file.model.bones.0[2].extra.append = append(1, 0.5, true);
+        let skeleton = file.model.skeleton().unwrap();
+        let matrices = pose.evaluate(&skeleton);
+        let tip = matrices.skinning[2].transform_point(Point3::new(0.0, 2.0, 0.0));
+        let (sin, cos) = Deg(45.0f32).sin_cos();
+        assert_near(tip, Point3::new(1.0 - cos, -sin, 0.0));
+    }
+
+    #[test]
+    fn pose_applies_append_before_own_rotation() {
+        let mut file = sample_file(1, 2, 2.0);
+        {
+            let bones = &mut file.model.bones.0;
+            bones[1].parent_id = Index(-1);
+            bones[1].extra.append = Some(AppendTransform { parent: Index(0), ratio: 1.0, rotate: true, translate: false, local: false });
+        }
+        let skeleton = file.model.skeleton().unwrap();
+        let mut pose = Pose::rest(&skeleton);
+        pose.bones[0].rotation = Quaternion::from_angle_x(Deg(90.0));
+        pose.bones[1].rotation = Quaternion::from_angle_z(Deg(90.0));
+        let matrices = pose.evaluate(&skeleton);
+
+        // (0, 1, 0) in bone 1's space turns to z about x, which the turn about z keeps
+        let tip = matrices.world[1].transform_point(Point3::new(0.0, 1.0, 0.0));
+        assert_near(tip, Point3::new(0.0, 1.0, 1.0));
+    }
+}
@@ -0,0 +1,233 @@
+//! The bone hierarchy of a model, with the order MMD deforms the bones in.
+
+use super::validate::{Finding, FindingKind, Severity};
+use super::{Bone, BoneFlags, Model};
+use error::WarningKind;
+
+use std::collections::HashMap;
+
+/// The tree of `Model::bones`, by index
+#[derive(Debug, Clone)]
+pub struct Skeleton<'a> {
+    bones: &'a [Bone],
+    parents: Vec<Option<usize>>,
+    children: Vec<Vec<usize>>,
+    roots: Vec<usize>,
+    names: HashMap<&'a str, usize>,
+    names_en: HashMap<&'a str, usize>,
+    order: Vec<usize>,
+    /// The start of the bones deformed after physics in `order`
+    physics: usize,
+}
+
+impl Model {
+    /// Fails on a parent out of range or a cycle of parents,
+    /// with the first bone which has one
+    pub fn skeleton<'a>(&'a self) -> Result<Skeleton<'a>, Finding> {
+        Skeleton::new(self)
+    }
+}
+
+impl<'a> Skeleton<'a> {
+    pub fn new(model: &'a Model) -> Result<Skeleton<'a>, Finding> {
+        let bones = &model.bones.0[..];
+        let error = |index, kind| Finding { severity: Severity::Error, section: "bones", index: Some(index), kind };
+
+        let mut parents = Vec::with_capacity(bones.len());
+        let mut children = vec![Vec::new(); bones.len()];
+        let mut roots = Vec::new();
+        for (i, bone) in bones.iter().enumerate() {
+            let p = bone.parent_id.0;
+            if p < 0 {
+                parents.push(None);
+                roots.push(i);
+            } else if (p as usize) < bones.len() {
+                parents.push(Some(p as usize));
+                children[p as usize].push(i);
+            } else {
+                let kind = WarningKind::IndexOutOfRange { target: "bones", index: p };
+                return Err(error(i, FindingKind::Load(kind)));
+            }
+        }
+        if let Some(&i) = model.bone_cycles().first() {
+            return Err(error(i, FindingKind::BoneCycle));
+        }
+
+        // The first bone of a name wins, as in MMD
+        let mut names = HashMap::new();
+        let mut names_en = HashMap::new();
+        for (i, bone) in bones.iter().enumerate().rev() {
+            names.insert(&bone.name.0[..], i);
+            names_en.insert(&bone.name_en.0[..], i);
+        }
+
+        let after_physics = |i: usize| bones[i].flags.0.contains(BoneFlags::DeformAfterPhysics);
+        let mut order = (0..bones.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&i| (after_physics(i), bones[i].deform_depth, i));
+        let physics = order.iter().position(|&i| after_physics(i)).unwrap_or(order.len());
+
+        Ok(Skeleton { bones, parents, children, roots, names, names_en, order, physics })
+    }
+
+    pub fn bones(&self) -> &'a [Bone] {
+        self.bones
+    }
+
+    pub fn len(&self) -> usize {
+        self.bones.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bones.is_empty()
+    }
+
+    pub fn parent(&self, i: usize) -> Option<usize> {
+        self.parents[i]
+    }
+
+    /// In order of index
+    pub fn children(&self, i: usize) -> &[usize] {
+        &self.children[i]
+    }
+
+    /// The bones without a parent, in order of index
+    pub fn roots(&self) -> &[usize] {
+        &self.roots
+    }
+
+    /// The parent, its parent and so on up to a root
+    pub fn ancestors<'s>(&'s self, i: usize) -> Ancestors<'s, 'a> {
+        Ancestors { skeleton: self, next: self.parents[i] }
+    }
+
+    /// Looks up the Japanese names, then the English ones.
+    /// The first bone wins if several have the name.
+    pub fn find(&self, name: &str) -> Option<usize> {
+        self.find_jp(name).or_else(|| self.find_en(name))
+    }
+
+    pub fn find_jp(&self, name: &str) -> Option<usize> {
+        self.names.get(name).cloned()
+    }
+
+    pub fn find_en(&self, name: &str) -> Option<usize> {
+        if name.is_empty() {
+            // Most bones have no English name
+            return None;
+        }
+        self.names_en.get(name).cloned()
+    }
+
+    /// Every bone, each before its children, starting from the roots
+    pub fn depth_first<'s>(&'s self) -> DepthFirst<'s, 'a> {
+        DepthFirst { skeleton: self, stack: self.roots.iter().rev().cloned().collect() }
+    }
+
+    /// The order MMD deforms the bones in: the ones deformed after physics last,
+    /// then by `deform_depth`, then by index. A parent may come after its child
+    /// if its `deform_depth` is larger, in which case MMD uses the parent's
+    /// transform from before the update.
+    pub fn evaluation_order(&self) -> &[usize] {
+        &self.order
+    }
+
+    /// The part of `evaluation_order` before physics
+    pub fn before_physics(&self) -> &[usize] {
+        &self.order[..self.physics]
+    }
+
+    /// The part of `evaluation_order` after physics
+    pub fn after_physics(&self) -> &[usize] {
+        &self.order[self.physics..]
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Ancestors<'s, 'a: 's> {
+    skeleton: &'s Skeleton<'a>,
+    next: Option<usize>,
+}
+
+impl<'s, 'a> Iterator for Ancestors<'s, 'a> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        let i = self.next?;
+        self.next = self.skeleton.parents[i];
+        Some(i)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DepthFirst<'s, 'a: 's> {
+    skeleton: &'s Skeleton<'a>,
+    stack: Vec<usize>,
+}
+
+impl<'s, 'a> Iterator for DepthFirst<'s, 'a> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        let i = self.stack.pop()?;
+        self.stack.extend(self.skeleton.children[i].iter().rev());
+        Some(i)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.stack.len(), Some(self.skeleton.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::sample_file;
+    use super::super::{Index, ModeSet};
+    use super::*;
+
+    #[test]
+    fn skeleton_of_sample() {
+        let mut file = sample_file(1, 2, 2.0);
+        let bones = &mut file.model.bones.0;
+        bones[1].deform_depth = 1;
+        bones[2].flags = ModeSet::new(bones[2].flags.0 | BoneFlags::DeformAfterPhysics);
+        let skeleton = file.model.skeleton().unwrap();
+
+        assert_eq!(skeleton.roots(), &[0]);
+        assert_eq!(skeleton.children(0), &[1, 2]);
+        assert_eq!(skeleton.parent(2), Some(0));
+        assert_eq!(skeleton.ancestors(1).collect::<Vec<_>>(), vec![0]);
+        assert_eq!(skeleton.find("腕"), Some(1));
+        assert_eq!(skeleton.find("nothing"), None);
+        assert_eq!(skeleton.depth_first().collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert_eq!(skeleton.before_physics(), &[0, 1]);
+        assert_eq!(skeleton.after_physics(), &[2]);
+    }
+
+    #[test]
+    fn deform_depth_overrides_index() {
+        let mut file = sample_file(1, 2, 2.0);
+        {
+            let bones = &mut file.model.bones.0;
+            bones[0].deform_depth = 2;
+            bones[1].deform_depth = 1;
+        }
+        let skeleton = file.model.skeleton().unwrap();
+
+        // The parent comes after both of its children
+        assert_eq!(skeleton.evaluation_order(), &[2, 1, 0]);
+        assert_eq!(skeleton.before_physics(), &[2, 1, 0]);
+        assert!(skeleton.after_physics().is_empty());
+    }
+
+    #[test]
+    fn skeleton_rejects_cycles() {
+        let mut file = sample_file(1, 2, 2.0);
+        file.model.bones.0[0].parent_id = Index(2);
+        let e = file.model.skeleton().unwrap_err();
+        assert_eq!((e.index, e.kind), (Some(0), FindingKind::BoneCycle));
+
+        file.model.bones.0[0].parent_id = Index(3);
+        let e = file.model.skeleton().unwrap_err();
+        assert_eq!(e.kind, FindingKind::Load(WarningKind::IndexOutOfRange { target: "bones", index: 3 }));
+    }
+}
@@ -0,0 +1,200 @@
+//! Checks of a whole model, e.g. to reject broken assets in CI.
+
+use super::{Diagnostics, Model, ToonMode, SECTIONS};
+use error::WarningKind;
+
+use std::error;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// MMD loads the model, but it may not look as intended
+    Warning,
+    /// A reference or a count which MMD would fail on
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FindingKind {
+    /// A reference to an element which does not exist, or bone weights
+    /// which do not add up to 1, as reported by the loader
+    Load(WarningKind),
+    /// The number of face indices is not a multiple of 3
+    PartialTriangle(usize),
+    /// A `Material::num_vertex_indices` which is negative or not a multiple of 3
+    InvalidFaceCount(i32),
+    /// The sum of `Material::num_vertex_indices` is not the number of face indices
+    FaceCountMismatch { materials: i64, faces: usize },
+    /// A common toon other than toon01.bmp to toon10.bmp
+    InvalidCommonToon(i32),
+    /// The parents of the bone lead back to it
+    BoneCycle,
+}
+
+impl fmt::Display for FindingKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::FindingKind::*;
+        match *self {
+            Load(ref kind) => write!(f, "{}", kind),
+            PartialTriangle(n) => write!(f, "{} face indices do not make whole triangles", n),
+            InvalidFaceCount(n) => write!(f, "Invalid face index count {}", n),
+            FaceCountMismatch { materials, faces } => write!(f, "Materials use {} of {} face indices", materials, faces),
+            InvalidCommonToon(x) => write!(f, "Invalid common toon {}", x),
+            BoneCycle => write!(f, "The bone is its own ancestor"),
+        }
+    }
+}
+
+/// A problem found by `Model::validate`. `to_string` gives the message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub severity: Severity,
+    pub section: &'static str,
+    /// The element in `section`, if the problem is with one
+    pub index: Option<usize>,
+    pub kind: FindingKind,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        match self.index {
+            Some(index) => write!(f, "{}: {} (in {} #{})", severity, self.kind, self.section, index),
+            None => write!(f, "{}: {} (in {})", severity, self.kind, self.section),
+        }
+    }
+}
+
+impl error::Error for Finding {}
+
+impl Model {
+    /// Checks the references between the sections, the face counts of the
+    /// materials and the bone hierarchy. The findings are in file order.
+    pub fn validate(&self) -> Vec<Finding> {
+        let d = Diagnostics::new(false);
+        // Only fails in strict mode
+        let _ = self.check(&d, &[]);
+        let mut findings = d
+            .warnings
+            .into_inner()
+            .into_iter()
+            .map(|w| {
+                let severity = match w.kind {
+                    WarningKind::WeightSum(_) => Severity::Warning,
+                    _ => Severity::Error,
+                };
+                Finding { severity, section: w.section, index: w.index, kind: FindingKind::Load(w.kind) }
+            })
+            .collect::<Vec<_>>();
+        let mut error = |section, index, kind| findings.push(Finding { severity: Severity::Error, section, index, kind });
+
+        let faces = self.face_indices.0.len();
+        if faces % 3 != 0 {
+            error("faces", None, FindingKind::PartialTriangle(faces));
+        }
+
+        let mut materials = 0;
+        for (i, m) in self.materials.0.iter().enumerate() {
+            if m.num_vertex_indices < 0 || m.num_vertex_indices % 3 != 0 {
+                error("materials", Some(i), FindingKind::InvalidFaceCount(m.num_vertex_indices));
+            }
+            materials += i64::from(m.num_vertex_indices);
+            if m.toon_mode == ToonMode::Common && (m.toon_texture_id.0 < 0 || m.toon_texture_id.0 > 9) {
+                error("materials", Some(i), FindingKind::InvalidCommonToon(m.toon_texture_id.0));
+            }
+        }
+        if materials != faces as i64 {
+            error("materials", None, FindingKind::FaceCountMismatch { materials, faces });
+        }
+
+        for i in self.bone_cycles() {
+            error("bones", Some(i), FindingKind::BoneCycle);
+        }
+
+        let order = |f: &Finding| SECTIONS.iter().position(|&s| s == f.section);
+        findings.sort_by_key(|f| (order(f), f.index));
+        findings
+    }
+
+    /// The bones on a cycle of parents, in order.
+    /// Parents out of range are reported by `check`, and end the walk here.
+    pub(super) fn bone_cycles(&self) -> Vec<usize> {
+        const UNVISITED: usize = !0;
+        let bones = &self.bones.0;
+        let parent = |i: usize| {
+            let p = bones[i].parent_id.0;
+            if p >= 0 && (p as usize) < bones.len() {
+                Some(p as usize)
+            } else {
+                None
+            }
+        };
+        // The walk which first reached each bone
+        let mut walk = vec![UNVISITED; bones.len()];
+        let mut on_cycle = vec![false; bones.len()];
+        for start in 0..bones.len() {
+            let mut i = start;
+            while walk[i] == UNVISITED {
+                walk[i] = start;
+                match parent(i) {
+                    Some(p) => i = p,
+                    None => break,
+                }
+            }
+            // Reached a bone of this walk again, so it is on a cycle
+            if walk[i] == start && parent(i).is_some() && !on_cycle[i] {
+                let first = i;
+                loop {
+                    on_cycle[i] = true;
+                    i = parent(i).unwrap();
+                    if i == first {
+                        break;
+                    }
+                }
+            }
+        }
+        (0..bones.len()).filter(|&i| on_cycle[i]).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::sample_file;
+    use super::super::Index;
+    use super::*;
+
+    #[test]
+    fn validate_sample() {
+        let file = sample_file(1, 2, 2.0);
+        assert_eq!(file.model.validate(), vec![]);
+    }
+
+    #[test]
+    fn validate_reports_broken_models() {
+        let mut file = sample_file(1, 2, 2.0);
+        let model = &mut file.model;
+        model.face_indices.0[4] = 5;
+        model.face_indices.0.push(0);
+        model.materials.0[0].num_vertex_indices = 4;
+        model.bones.0[0].parent_id = Index(2);
+        model.bones.0[2].parent_id = Index(0);
+
+        let findings = model.validate();
+        let summary = findings.iter().map(|f| (f.severity, f.section, f.index, f.kind.clone())).collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                (Severity::Error, "faces", None, FindingKind::PartialTriangle(7)),
+                (Severity::Error, "faces", Some(4), FindingKind::Load(WarningKind::IndexOutOfRange { target: "vertices", index: 5 })),
+                (Severity::Error, "materials", None, FindingKind::FaceCountMismatch { materials: 4, faces: 7 }),
+                (Severity::Error, "materials", Some(0), FindingKind::InvalidFaceCount(4)),
+                (Severity::Error, "bones", Some(0), FindingKind::BoneCycle),
+                (Severity::Error, "bones", Some(2), FindingKind::BoneCycle),
+            ]
+        );
+        assert_eq!(findings[1].to_string(), "error: Index 5 is out of range of vertices (in faces #4)");
+    }
+}
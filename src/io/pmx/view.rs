@@ -0,0 +1,322 @@
+//! Vertices and faces decoded on the fly from the bytes of a PMX file,
+//! e.g. a memory-mapped one, without loading the whole model.
+//!
+//! ```no_run
+//! # fn main() -> mmd::Result<()> {
+//! use mmd::io::pmx::view::PmxView;
+//!
+//! let bytes = std::fs::read("model.pmx")?;
+//! let view = PmxView::new(&bytes)?;
+//! for v in view.vertices() {
+//!     let w = v.weights();
+//!     println!("{:?} {:?} {:?}", v.position(), w.bones, w.weights);
+//! }
+//! let triangles = view.faces().collect::<Vec<u32>>();
+//! # Ok(())
+//! # }
+//! ```
+
+use super::super::Tracker;
+use super::bulk::{read_index, read_vec2, read_vec3, read_vec4, read_vertex_index, weight_layout};
+use super::read_header;
+use error::Result;
+
+use std::ops::Range;
+use std::slice::ChunksExact;
+
+use byteorder::{ByteOrder, LE};
+use cgmath::{Vector2, Vector3, Vector4};
+use pod_io::{Decode, Nil};
+
+/// Borrows the vertex and face sections of a PMX file.
+/// Only the start of each vertex is stored, since their size varies.
+#[derive(Debug)]
+pub struct PmxView<'a> {
+    version: f32,
+    additional: usize,
+    bone_index_size: usize,
+    vertex_index_size: usize,
+    vertices: &'a [u8],
+    /// The offsets of the vertices in `vertices`, and the end of the last one
+    vertex_offsets: Vec<usize>,
+    faces: &'a [u8],
+}
+
+impl<'a> PmxView<'a> {
+    /// Reads the header and walks the vertices to find where each one starts.
+    /// The sections after the faces are not read.
+    pub fn new(bytes: &'a [u8]) -> Result<PmxView<'a>> {
+        let r = &mut Tracker::new(bytes);
+        let (_, h, helper) = read_header(r, false)?;
+        r.section("model name", |r| skip_name(r))?;
+        r.section("comment", |r| skip_name(r))?;
+
+        let additional = h.additional as usize;
+        let bone = h.bone_index_size as usize;
+        let (vertices, vertex_offsets) = r.section("vertices", |r| {
+            let n = u32::decode::<LE>(r, Nil)? as usize;
+            let start = r.inner;
+            let fixed = 32 + 16 * additional;
+            // The smallest vertex is BDEF1, so a corrupt count cannot reserve
+            // more than the file could hold.
+            let mut offsets = Vec::with_capacity(n.min(start.len() / (fixed + 1 + bone + 4)) + 1);
+            let mut end = 0;
+            for _ in 0..n {
+                offsets.push(end);
+                let ty = r.borrow(fixed + 1)?[fixed];
+                let (bones, weights) = weight_layout(ty)?;
+                r.borrow(bones * bone + weights + 4)?;
+                end = start.len() - r.inner.len();
+            }
+            offsets.push(end);
+            Ok((&start[..end], offsets))
+        })?;
+        let vertex_index_size = h.vertex_index_size as usize;
+        let faces = r.section("faces", |r| {
+            let n = u32::decode::<LE>(r, Nil)? as usize;
+            r.borrow(n.saturating_mul(vertex_index_size))
+        })?;
+
+        Ok(PmxView {
+            version: helper.version,
+            additional,
+            bone_index_size: bone,
+            vertex_index_size,
+            vertices,
+            vertex_offsets,
+            faces,
+        })
+    }
+
+    pub fn version(&self) -> f32 {
+        self.version
+    }
+
+    pub fn num_vertices(&self) -> usize {
+        self.vertex_offsets.len() - 1
+    }
+
+    pub fn vertex(&self, i: usize) -> Option<VertexView<'a>> {
+        if i >= self.num_vertices() {
+            return None;
+        }
+        let bytes = &self.vertices[self.vertex_offsets[i]..self.vertex_offsets[i + 1]];
+        Some(VertexView { bytes, additional: self.additional, bone_index_size: self.bone_index_size })
+    }
+
+    pub fn vertices<'v>(&'v self) -> Vertices<'v> {
+        Vertices { view: self, range: 0..self.num_vertices() }
+    }
+
+    /// The number of vertex indices, three per triangle
+    pub fn num_face_indices(&self) -> usize {
+        self.faces.len() / self.vertex_index_size
+    }
+
+    pub fn face_index(&self, i: usize) -> Option<u32> {
+        let size = self.vertex_index_size;
+        self.faces.get(i * size..(i + 1) * size).map(|b| read_vertex_index(b, size))
+    }
+
+    /// The vertex indices widened to `u32`
+    pub fn faces(&self) -> Faces<'a> {
+        Faces { chunks: self.faces.chunks_exact(self.vertex_index_size), size: self.vertex_index_size }
+    }
+}
+
+fn skip_name(r: &mut Tracker<&[u8]>) -> ::std::io::Result<()> {
+    for _ in 0..2 {
+        let n = u32::decode::<LE>(r, Nil)?;
+        r.borrow(n as usize)?;
+    }
+    Ok(())
+}
+
+/// A vertex, decoded field by field on access
+#[derive(Debug, Clone, Copy)]
+pub struct VertexView<'a> {
+    bytes: &'a [u8],
+    additional: usize,
+    bone_index_size: usize,
+}
+
+impl<'a> VertexView<'a> {
+    pub fn position(&self) -> Vector3<f32> {
+        read_vec3(self.bytes)
+    }
+
+    pub fn normal(&self) -> Vector3<f32> {
+        read_vec3(&self.bytes[12..])
+    }
+
+    pub fn uv(&self) -> Vector2<f32> {
+        read_vec2(&self.bytes[24..])
+    }
+
+    /// `None` past the number of additional UVs in the header
+    pub fn additional(&self, i: usize) -> Option<Vector4<f32>> {
+        if i >= self.additional {
+            return None;
+        }
+        Some(read_vec4(&self.bytes[32 + 16 * i..]))
+    }
+
+    pub fn weights(&self) -> Weights {
+        let size = self.bone_index_size;
+        let start = 32 + 16 * self.additional;
+        let ty = self.bytes[start];
+        let b = &self.bytes[start + 1..];
+        let index = |i: usize| read_index(&b[i * size..], size);
+        let (ty, bones, weights, sdef) = match ty {
+            0 => (WeightType::BDEF1, [index(0), -1, -1, -1], [1.0, 0.0, 0.0, 0.0], None),
+            1 | 3 => {
+                let w = LE::read_f32(&b[2 * size..]);
+                let bones = [index(0), index(1), -1, -1];
+                if ty == 1 {
+                    (WeightType::BDEF2, bones, [w, 1.0 - w, 0.0, 0.0], None)
+                } else {
+                    let v = &b[2 * size + 4..];
+                    let sdef = Sdef { c: read_vec3(v), r0: read_vec3(&v[12..]), r1: read_vec3(&v[24..]) };
+                    (WeightType::SDEF, bones, [w, 1.0 - w, 0.0, 0.0], Some(sdef))
+                }
+            }
+            _ => {
+                let bones = [index(0), index(1), index(2), index(3)];
+                let mut weights = [0.0; 4];
+                LE::read_f32_into(&b[4 * size..4 * size + 16], &mut weights);
+                let ty = if ty == 2 { WeightType::BDEF4 } else { WeightType::QDEF };
+                (ty, bones, weights, None)
+            }
+        };
+        Weights { ty, bones, weights, sdef }
+    }
+
+    pub fn edge_scale(&self) -> f32 {
+        LE::read_f32(&self.bytes[self.bytes.len() - 4..])
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WeightType {
+    BDEF1,
+    BDEF2,
+    BDEF4,
+    SDEF,
+    QDEF,
+}
+
+/// The bone weights of any type in one layout.
+/// Unused bones are -1 with a weight of 0, and BDEF1 has a weight of 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Weights {
+    pub ty: WeightType,
+    pub bones: [i32; 4],
+    pub weights: [f32; 4],
+    /// Only for SDEF
+    pub sdef: Option<Sdef>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sdef {
+    pub c: Vector3<f32>,
+    pub r0: Vector3<f32>,
+    pub r1: Vector3<f32>,
+}
+
+/// The vertices of a `PmxView`, in order
+#[derive(Debug, Clone)]
+pub struct Vertices<'v> {
+    view: &'v PmxView<'v>,
+    range: Range<usize>,
+}
+
+impl<'v> Iterator for Vertices<'v> {
+    type Item = VertexView<'v>;
+
+    fn next(&mut self) -> Option<VertexView<'v>> {
+        self.range.next().and_then(|i| self.view.vertex(i))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.range.size_hint()
+    }
+}
+
+impl<'v> ExactSizeIterator for Vertices<'v> {}
+
+/// The vertex indices of a `PmxView`, widened to `u32`
+#[derive(Debug, Clone)]
+pub struct Faces<'a> {
+    chunks: ChunksExact<'a, u8>,
+    size: usize,
+}
+
+impl<'a> Iterator for Faces<'a> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        let size = self.size;
+        self.chunks.next().map(|b| read_vertex_index(b, size))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.chunks.size_hint()
+    }
+}
+
+impl<'a> ExactSizeIterator for Faces<'a> {}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::sample_file;
+    use super::super::{BoneWeight, PmxFile};
+    use super::*;
+
+    #[test]
+    fn view_matches_loader() {
+        for &(encode, index_size, version) in &[(0, 1, 2.0), (1, 2, 2.0), (0, 4, 2.1)] {
+            let mut bytes = Vec::new();
+            sample_file(encode, index_size, version).save(&mut bytes).unwrap();
+            let pmx = PmxFile::from_bytes(&bytes).unwrap();
+            let view = PmxView::new(&bytes).unwrap();
+
+            assert_eq!(view.num_vertices(), pmx.model.vertices.0.len());
+            for (v, expected) in view.vertices().zip(&pmx.model.vertices.0) {
+                assert_eq!(v.position(), expected.position.0);
+                assert_eq!(v.normal(), expected.normal.0);
+                assert_eq!(v.uv(), expected.uv.0);
+                assert_eq!(v.additional(0), Some(expected.additional.0[0].0));
+                assert_eq!(v.additional(1), None);
+                assert_eq!(v.edge_scale(), expected.edge_scale);
+                let w = v.weights();
+                match expected.bone_weight {
+                    BoneWeight::BDEF1 { index } => assert_eq!(w.bones[0], index),
+                    BoneWeight::BDEF2 { indices, weight } => {
+                        assert_eq!(&w.bones[..2], &indices);
+                        assert_eq!(w.weights, [weight, 1.0 - weight, 0.0, 0.0]);
+                    }
+                    BoneWeight::SDEF { indices, ref r0, .. } => {
+                        assert_eq!(&w.bones[..2], &indices);
+                        assert_eq!(w.sdef.unwrap().r0, r0.0);
+                    }
+                    BoneWeight::BDEF4 { indices, weights } | BoneWeight::QDEF { indices, weights } => {
+                        assert_eq!((w.bones, w.weights), (indices, weights));
+                    }
+                }
+            }
+            assert_eq!(view.vertex(5).map(|v| v.position()), None);
+
+            assert_eq!(view.faces().collect::<Vec<_>>(), pmx.model.face_indices.0);
+            assert_eq!(view.face_index(5), Some(4));
+            assert_eq!(view.face_index(6), None);
+        }
+    }
+
+    #[test]
+    fn view_rejects_truncated_vertices() {
+        let mut bytes = Vec::new();
+        sample_file(1, 2, 2.0).save(&mut bytes).unwrap();
+        let e = PmxView::new(&bytes[..100]).unwrap_err();
+        assert_eq!(e.section(), Some("vertices"));
+    }
+}
@@ -0,0 +1,536 @@
+// Reference:
+// http://blog.goo.ne.jp/torisu_tetosuki/e/bc9f1c4d597341b394bd02b64597499d
+// https://github.com/benikabocha/saba
+use super::{err, is_eof, read_bool, Load, Save, Tracker};
+use super::newtypes::*;
+use super::pmd::PmdString;
+use error::{self, ErrorKind};
+use std::io::{BufRead, BufReader, Read, Result, Seek, Write};
+
+use byteorder::{ByteOrder, WriteBytesExt, LE};
+use num_traits::FromPrimitive;
+use pod_io::{Decode, Nil};
+
+const MAGIC: &[u8] = b"Vocaloid Motion Data 0002";
+const MAGIC_OLD: &[u8] = b"Vocaloid Motion Data file";
+
+fn read_section<R: BufRead, T: Decode<R, Nil>>(r: &mut R) -> Result<Array<T>> {
+    if is_eof(r)? {
+        return Ok(Array(Vec::new()));
+    }
+    let n = u32::decode::<LE>(r, Nil)? as usize;
+    Array::decode::<LE>(r, n)
+}
+
+fn write_section<W: Write, T: Encode<W, Nil>>(w: &mut W, a: &Array<T>) -> Result<()> {
+    (a.0.len() as u32).encode::<LE>(w, Nil)?;
+    a.0.iter().try_for_each(|x| x.encode::<LE>(w, Nil))
+}
+
+/// Every section after the model name may be missing in older files,
+/// in which case it is left empty.
+#[derive(Debug)]
+pub struct VmdFile {
+    magic: [u8; 30],
+    pub model_name: PmdString,
+    pub bone_keyframes: Array<BoneKeyframe>,
+    pub morph_keyframes: Array<MorphKeyframe>,
+    pub camera_keyframes: Array<CameraKeyframe>,
+    pub light_keyframes: Array<LightKeyframe>,
+    pub shadow_keyframes: Array<ShadowKeyframe>,
+    pub ik_keyframes: Array<IKKeyframe>,
+}
+
+impl VmdFile {
+    /// An empty motion for the model
+    pub fn new<S: Into<String>>(model_name: S) -> VmdFile {
+        let mut magic = [0u8; 30];
+        magic[..MAGIC.len()].copy_from_slice(MAGIC);
+        VmdFile {
+            magic,
+            model_name: PmdString(model_name.into()),
+            bone_keyframes: Array(Vec::new()),
+            morph_keyframes: Array(Vec::new()),
+            camera_keyframes: Array(Vec::new()),
+            light_keyframes: Array(Vec::new()),
+            shadow_keyframes: Array(Vec::new()),
+            ik_keyframes: Array(Vec::new()),
+        }
+    }
+}
+
+fn read_header<R: Read>(r: &mut Tracker<R>) -> error::Result<([u8; 30], PmdString)> {
+    let magic = r.section("header", |r| {
+        let mut magic = [0u8; 30];
+        r.read_exact(&mut magic)?;
+        Ok(magic)
+    })?;
+    let name_len = if magic.starts_with(MAGIC) {
+        20
+    } else if magic.starts_with(MAGIC_OLD) {
+        10
+    } else {
+        return Err(r.fail("header", ErrorKind::BadMagic));
+    };
+    let model_name = r.section("header", |r| PmdString::decode::<LE>(r, name_len))?;
+    Ok((magic, model_name))
+}
+
+impl Load for VmdFile {
+    fn load<R: Read>(rdr: &mut R) -> error::Result<VmdFile> {
+        let r = &mut Tracker::new(BufReader::new(rdr));
+        let (magic, model_name) = read_header(r)?;
+        let bone_keyframes = r.section("bone keyframes", read_section)?;
+        let morph_keyframes = r.section("morph keyframes", read_section)?;
+        let camera_keyframes = r.section("camera keyframes", read_section)?;
+        let light_keyframes = r.section("light keyframes", read_section)?;
+        let shadow_keyframes = r.section("shadow keyframes", read_section)?;
+        let ik_keyframes = r.section("ik keyframes", read_section)?;
+        Ok(VmdFile {
+            magic,
+            model_name,
+            bone_keyframes,
+            morph_keyframes,
+            camera_keyframes,
+            light_keyframes,
+            shadow_keyframes,
+            ik_keyframes,
+        })
+    }
+}
+
+/// What `VmdFile::probe` reads without decoding the keyframes
+#[derive(Debug)]
+pub struct VmdProbe {
+    pub model_name: PmdString,
+    pub num_bone_keyframes: usize,
+    pub num_morph_keyframes: usize,
+    pub num_camera_keyframes: usize,
+    pub num_light_keyframes: usize,
+    pub num_shadow_keyframes: usize,
+    pub num_ik_keyframes: usize,
+}
+
+impl VmdProbe {
+    pub(super) fn probe<R: Read + Seek>(rdr: &mut R) -> error::Result<VmdProbe> {
+        let r = &mut Tracker::new(BufReader::new(rdr));
+        let (_, model_name) = read_header(r)?;
+        let skip_section = |r: &mut Tracker<BufReader<&mut R>>, size: u64| {
+            if is_eof(r)? {
+                return Ok(0);
+            }
+            let n = u32::decode::<LE>(r, Nil)?;
+            r.skip(u64::from(n) * size)?;
+            Ok(n as usize)
+        };
+        Ok(VmdProbe {
+            model_name,
+            num_bone_keyframes: r.section("bone keyframes", |r| skip_section(r, 111))?,
+            num_morph_keyframes: r.section("morph keyframes", |r| skip_section(r, 23))?,
+            num_camera_keyframes: r.section("camera keyframes", |r| skip_section(r, 61))?,
+            num_light_keyframes: r.section("light keyframes", |r| skip_section(r, 28))?,
+            num_shadow_keyframes: r.section("shadow keyframes", |r| skip_section(r, 9))?,
+            num_ik_keyframes: r.section("ik keyframes", |r| {
+                if is_eof(r)? {
+                    return Ok(0);
+                }
+                let n = u32::decode::<LE>(r, Nil)?;
+                for _ in 0..n {
+                    r.skip(5)?;
+                    let iks = u32::decode::<LE>(r, Nil)?;
+                    r.skip(u64::from(iks) * 21)?;
+                }
+                Ok(n as usize)
+            })?,
+        })
+    }
+}
+
+/// Names are encoded in Shift-JIS, and saving fails if any of them does not fit.
+impl Save for VmdFile {
+    fn save<W: Write>(&self, w: &mut W) -> error::Result<()> {
+        let w = &mut Tracker::new(w);
+        w.section("header", |w| {
+            w.write_all(&self.magic)?;
+            let name_len = if self.magic.starts_with(MAGIC_OLD) { 10 } else { 20 };
+            self.model_name.encode::<LE>(w, name_len)
+        })?;
+        w.section("bone keyframes", |w| write_section(w, &self.bone_keyframes))?;
+        w.section("morph keyframes", |w| write_section(w, &self.morph_keyframes))?;
+        w.section("camera keyframes", |w| write_section(w, &self.camera_keyframes))?;
+        w.section("light keyframes", |w| write_section(w, &self.light_keyframes))?;
+        w.section("shadow keyframes", |w| write_section(w, &self.shadow_keyframes))?;
+        w.section("ik keyframes", |w| write_section(w, &self.ik_keyframes))
+    }
+}
+
+#[derive(Debug)]
+pub struct BoneKeyframe {
+    pub name: PmdString,
+    pub frame: u32,
+    pub translation: Vec3,
+    /// Quaternion (x, y, z, w)
+    pub rotation: Vec4,
+    /// Bezier control points (x1, y1, x2, y2) of X, Y, Z and rotation,
+    /// stored as `[kind + 4 * point]` in the first 16 bytes.
+    /// The rest are copies shifted by one byte for compatibility.
+    pub interpolation: [u8; 64],
+}
+
+impl<R: Read> Decode<R, Nil> for BoneKeyframe {
+    fn decode<B: ByteOrder>(r: &mut R, _p: Nil) -> Result<BoneKeyframe> {
+        let name = PmdString::decode::<LE>(r, 15)?;
+        let frame = u32::decode::<LE>(r, Nil)?;
+        let translation = Vec3::decode::<LE>(r, Nil)?;
+        let rotation = Vec4::decode::<LE>(r, Nil)?;
+        let mut interpolation = [0u8; 64];
+        r.read_exact(&mut interpolation)?;
+        Ok(BoneKeyframe { name, frame, translation, rotation, interpolation })
+    }
+}
+
+impl<W: Write> Encode<W, Nil> for BoneKeyframe {
+    fn encode<B: ByteOrder>(&self, w: &mut W, _p: Nil) -> Result<()> {
+        self.name.encode::<LE>(w, 15)?;
+        self.frame.encode::<LE>(w, Nil)?;
+        self.translation.encode::<LE>(w, Nil)?;
+        self.rotation.encode::<LE>(w, Nil)?;
+        w.write_all(&self.interpolation)
+    }
+}
+
+#[derive(Debug, Decode)]
+pub struct MorphKeyframe {
+    #[Arg = "15"]
+    pub name: PmdString,
+    pub frame: u32,
+    pub weight: f32,
+}
+
+impl<W: Write> Encode<W, Nil> for MorphKeyframe {
+    fn encode<B: ByteOrder>(&self, w: &mut W, _p: Nil) -> Result<()> {
+        self.name.encode::<LE>(w, 15)?;
+        self.frame.encode::<LE>(w, Nil)?;
+        self.weight.encode::<LE>(w, Nil)
+    }
+}
+
+#[derive(Debug)]
+pub struct CameraKeyframe {
+    pub frame: u32,
+    /// Negative when the camera is in front of the target
+    pub distance: f32,
+    /// The target position
+    pub position: Vec3,
+    /// Euler angles in radians
+    pub rotation: Vec3,
+    /// Bezier control points (x1, x2, y1, y2) of X, Y, Z, rotation, distance and fov
+    pub interpolation: [u8; 24],
+    /// In degrees
+    pub fov: u32,
+    pub perspective: bool,
+}
+
+impl<R: Read> Decode<R, Nil> for CameraKeyframe {
+    fn decode<B: ByteOrder>(r: &mut R, _p: Nil) -> Result<CameraKeyframe> {
+        let frame = u32::decode::<LE>(r, Nil)?;
+        let distance = f32::decode::<LE>(r, Nil)?;
+        let position = Vec3::decode::<LE>(r, Nil)?;
+        let rotation = Vec3::decode::<LE>(r, Nil)?;
+        let mut interpolation = [0u8; 24];
+        r.read_exact(&mut interpolation)?;
+        let fov = u32::decode::<LE>(r, Nil)?;
+        // 0: on, 1: off
+        let perspective = !read_bool(r)?;
+        Ok(CameraKeyframe { frame, distance, position, rotation, interpolation, fov, perspective })
+    }
+}
+
+impl<W: Write> Encode<W, Nil> for CameraKeyframe {
+    fn encode<B: ByteOrder>(&self, w: &mut W, _p: Nil) -> Result<()> {
+        self.frame.encode::<LE>(w, Nil)?;
+        self.distance.encode::<LE>(w, Nil)?;
+        self.position.encode::<LE>(w, Nil)?;
+        self.rotation.encode::<LE>(w, Nil)?;
+        w.write_all(&self.interpolation)?;
+        self.fov.encode::<LE>(w, Nil)?;
+        w.write_u8(if self.perspective { 0 } else { 1 })
+    }
+}
+
+#[derive(Debug, Decode)]
+pub struct LightKeyframe {
+    pub frame: u32,
+    /// RGB in [0, 1]
+    pub color: Vec3,
+    pub direction: Vec3,
+}
+
+impl<W: Write> Encode<W, Nil> for LightKeyframe {
+    fn encode<B: ByteOrder>(&self, w: &mut W, _p: Nil) -> Result<()> {
+        self.frame.encode::<LE>(w, Nil)?;
+        self.color.encode::<LE>(w, Nil)?;
+        self.direction.encode::<LE>(w, Nil)
+    }
+}
+
+#[derive(Primitive, Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum ShadowMode {
+    Off = 0,
+    Mode1 = 1,
+    Mode2 = 2,
+}
+
+impl_decode_mode!(ShadowMode, u8);
+impl_encode_mode!(ShadowMode, u8);
+
+#[derive(Debug, Decode)]
+pub struct ShadowKeyframe {
+    pub frame: u32,
+    pub mode: ShadowMode,
+    /// 0.1 - (shadow range in the UI) * 0.00001
+    pub distance: f32,
+}
+
+impl<W: Write> Encode<W, Nil> for ShadowKeyframe {
+    fn encode<B: ByteOrder>(&self, w: &mut W, _p: Nil) -> Result<()> {
+        self.frame.encode::<LE>(w, Nil)?;
+        self.mode.encode::<LE>(w, Nil)?;
+        self.distance.encode::<LE>(w, Nil)
+    }
+}
+
+#[derive(Debug)]
+pub struct IKKeyframe {
+    pub frame: u32,
+    pub visible: bool,
+    pub iks: Array<IKState>,
+}
+
+impl<R: Read> Decode<R, Nil> for IKKeyframe {
+    fn decode<B: ByteOrder>(r: &mut R, _p: Nil) -> Result<IKKeyframe> {
+        let frame = u32::decode::<LE>(r, Nil)?;
+        let visible = read_bool(r)?;
+        let n = u32::decode::<LE>(r, Nil)? as usize;
+        let iks = Array::decode::<LE>(r, n)?;
+        Ok(IKKeyframe { frame, visible, iks })
+    }
+}
+
+impl<W: Write> Encode<W, Nil> for IKKeyframe {
+    fn encode<B: ByteOrder>(&self, w: &mut W, _p: Nil) -> Result<()> {
+        self.frame.encode::<LE>(w, Nil)?;
+        (self.visible as u8).encode::<LE>(w, Nil)?;
+        write_section(w, &self.iks)
+    }
+}
+
+#[derive(Debug)]
+pub struct IKState {
+    pub name: PmdString,
+    pub enabled: bool,
+}
+
+impl<R: Read> Decode<R, Nil> for IKState {
+    fn decode<B: ByteOrder>(r: &mut R, _p: Nil) -> Result<IKState> {
+        let name = PmdString::decode::<LE>(r, 20)?;
+        let enabled = read_bool(r)?;
+        Ok(IKState { name, enabled })
+    }
+}
+
+impl<W: Write> Encode<W, Nil> for IKState {
+    fn encode<B: ByteOrder>(&self, w: &mut W, _p: Nil) -> Result<()> {
+        self.name.encode::<LE>(w, 20)?;
+        (self.enabled as u8).encode::<LE>(w, Nil)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Vector3, Vector4};
+    use encoding::{EncoderTrap, Encoding};
+    use encoding::all::WINDOWS_31J;
+    use std::io::Cursor;
+
+    fn write_str(w: &mut Vec<u8>, s: &str, len: usize) {
+        let mut buf = WINDOWS_31J.encode(s, EncoderTrap::Strict).unwrap();
+        buf.resize(len, 0);
+        w.write_all(&buf).unwrap();
+    }
+
+    #[test]
+    fn load_bone_and_morph_only() {
+        let mut data = Vec::new();
+        write_str(&mut data, "Vocaloid Motion Data 0002", 30);
+        write_str(&mut data, "初音ミク", 20);
+        data.write_u32::<LE>(1).unwrap();
+        write_str(&mut data, "右腕", 15);
+        data.write_u32::<LE>(30).unwrap();
+        for &f in &[0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0] {
+            data.write_f32::<LE>(f).unwrap();
+        }
+        data.write_all(&[20u8; 64]).unwrap();
+        data.write_u32::<LE>(1).unwrap();
+        write_str(&mut data, "あ", 15);
+        data.write_u32::<LE>(12).unwrap();
+        data.write_f32::<LE>(0.5).unwrap();
+
+        let vmd = VmdFile::load(&mut Cursor::new(data)).unwrap();
+        assert_eq!(vmd.model_name.0, "初音ミク");
+        let bone = &vmd.bone_keyframes.0[0];
+        assert_eq!(bone.name.0, "右腕");
+        assert_eq!(bone.frame, 30);
+        assert_eq!((bone.translation.0).y, 1.0);
+        assert_eq!((bone.rotation.0).w, 1.0);
+        assert_eq!(vmd.morph_keyframes.0[0].weight, 0.5);
+        assert!(vmd.camera_keyframes.0.is_empty());
+        assert!(vmd.ik_keyframes.0.is_empty());
+    }
+
+    #[test]
+    fn load_from_bytes_and_reader() {
+        let mut data = Vec::new();
+        write_str(&mut data, "Vocaloid Motion Data 0002", 30);
+        write_str(&mut data, "初音ミク", 20);
+        data.write_u32::<LE>(0).unwrap();
+        data.write_u32::<LE>(1).unwrap();
+        write_str(&mut data, "あ", 15);
+        data.write_u32::<LE>(12).unwrap();
+        data.write_f32::<LE>(0.5).unwrap();
+
+        let from_bytes = VmdFile::from_bytes(&data).unwrap();
+        let from_reader = VmdFile::from_reader(&mut Cursor::new(&data)).unwrap();
+        for vmd in &[from_bytes, from_reader] {
+            assert_eq!(vmd.model_name.0, "初音ミク");
+            assert_eq!(vmd.morph_keyframes.0[0].weight, 0.5);
+        }
+        assert!(VmdFile::from_bytes(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn load_camera_and_ik() {
+        let mut data = Vec::new();
+        write_str(&mut data, "Vocaloid Motion Data 0002", 30);
+        write_str(&mut data, "カメラ・照明", 20);
+        data.write_u32::<LE>(0).unwrap();
+        data.write_u32::<LE>(0).unwrap();
+        data.write_u32::<LE>(1).unwrap();
+        data.write_u32::<LE>(0).unwrap();
+        for &f in &[-45.0, 0.0, 10.0, 0.0, 0.0, 0.0, 0.0] {
+            data.write_f32::<LE>(f).unwrap();
+        }
+        data.write_all(&[20u8; 24]).unwrap();
+        data.write_u32::<LE>(30).unwrap();
+        data.write_u8(0).unwrap();
+        data.write_u32::<LE>(0).unwrap();
+        data.write_u32::<LE>(0).unwrap();
+        data.write_u32::<LE>(1).unwrap();
+        data.write_u32::<LE>(0).unwrap();
+        data.write_u8(1).unwrap();
+        data.write_u32::<LE>(1).unwrap();
+        write_str(&mut data, "左足ＩＫ", 20);
+        data.write_u8(0).unwrap();
+
+        let vmd = VmdFile::load(&mut Cursor::new(data)).unwrap();
+        let camera = &vmd.camera_keyframes.0[0];
+        assert_eq!(camera.distance, -45.0);
+        assert_eq!(camera.fov, 30);
+        assert!(camera.perspective);
+        let ik = &vmd.ik_keyframes.0[0];
+        assert!(ik.visible);
+        assert_eq!(ik.iks.0[0].name.0, "左足ＩＫ");
+        assert!(!ik.iks.0[0].enabled);
+    }
+
+    fn bone_keyframe(name: &str, frame: u32) -> BoneKeyframe {
+        BoneKeyframe {
+            name: PmdString(name.to_owned()),
+            frame,
+            translation: Vec3(Vector3::new(0.0, 1.0, 0.0)),
+            rotation: Vec4(Vector4::new(0.0, 0.0, 0.0, 1.0)),
+            interpolation: [20u8; 64],
+        }
+    }
+
+    #[test]
+    fn save_round_trip() {
+        let mut vmd = VmdFile::new("初音ミク");
+        vmd.bone_keyframes.0.push(bone_keyframe("左ひじ", 0));
+        vmd.bone_keyframes.0.push(bone_keyframe("左ひじ", 10));
+        vmd.morph_keyframes.0.push(MorphKeyframe { name: PmdString("まばたき".to_owned()), frame: 5, weight: 1.0 });
+        vmd.shadow_keyframes.0.push(ShadowKeyframe { frame: 0, mode: ShadowMode::Mode1, distance: 0.01 });
+        vmd.ik_keyframes.0.push(IKKeyframe {
+            frame: 0,
+            visible: true,
+            iks: Array(vec![IKState { name: PmdString("右足ＩＫ".to_owned()), enabled: false }]),
+        });
+
+        let mut saved = Vec::new();
+        vmd.save(&mut saved).unwrap();
+        let loaded = VmdFile::load(&mut Cursor::new(&saved)).unwrap();
+        assert_eq!(loaded.model_name.0, "初音ミク");
+        assert_eq!(loaded.bone_keyframes.0[1].name.0, "左ひじ");
+        assert_eq!(loaded.bone_keyframes.0[1].frame, 10);
+        assert_eq!(loaded.morph_keyframes.0[0].name.0, "まばたき");
+        assert_eq!(loaded.shadow_keyframes.0[0].mode, ShadowMode::Mode1);
+        assert_eq!(loaded.ik_keyframes.0[0].iks.0[0].name.0, "右足ＩＫ");
+
+        let mut resaved = Vec::new();
+        loaded.save(&mut resaved).unwrap();
+        assert_eq!(saved, resaved);
+    }
+
+    #[test]
+    fn save_rejects_long_name() {
+        // 8 characters take 16 bytes in Shift-JIS
+        let mut vmd = VmdFile::new("model");
+        vmd.bone_keyframes.0.push(bone_keyframe("左人指３先先先", 0));
+        assert!(vmd.save(&mut Vec::new()).is_ok());
+        vmd.bone_keyframes.0.push(bone_keyframe("左人指３先先先先", 0));
+        let e = vmd.save(&mut Vec::new()).unwrap_err();
+        match *e.kind() {
+            ErrorKind::NameTooLong { ref name, len: 15 } => assert_eq!(name, "左人指３先先先先"),
+            ref other => panic!("unexpected error {:?}", other),
+        }
+        assert_eq!(e.section(), Some("bone keyframes"));
+    }
+
+    #[test]
+    fn probe_counts() {
+        let mut vmd = VmdFile::new("model");
+        vmd.bone_keyframes.0.push(bone_keyframe("センター", 0));
+        vmd.bone_keyframes.0.push(bone_keyframe("センター", 10));
+        vmd.morph_keyframes.0.push(MorphKeyframe { name: PmdString("あ".to_owned()), frame: 3, weight: 1.0 });
+        vmd.ik_keyframes.0.push(IKKeyframe {
+            frame: 0,
+            visible: true,
+            iks: Array(vec![
+                IKState { name: PmdString("右足ＩＫ".to_owned()), enabled: false },
+                IKState { name: PmdString("左足ＩＫ".to_owned()), enabled: true },
+            ]),
+        });
+        vmd.ik_keyframes.0.push(IKKeyframe { frame: 5, visible: false, iks: Array(Vec::new()) });
+        let mut saved = Vec::new();
+        vmd.save(&mut saved).unwrap();
+
+        let probe = VmdFile::probe(&mut Cursor::new(&saved)).unwrap();
+        assert_eq!(probe.model_name.0, "model");
+        assert_eq!(
+            [probe.num_bone_keyframes, probe.num_morph_keyframes, probe.num_camera_keyframes, probe.num_ik_keyframes],
+            [2, 1, 0, 2]
+        );
+
+        // files from older versions end after the morphs
+        let probe = VmdFile::probe(&mut Cursor::new(&saved[..30 + 20 + 4 + 2 * 111 + 4 + 23])).unwrap();
+        assert_eq!([probe.num_morph_keyframes, probe.num_camera_keyframes, probe.num_ik_keyframes], [1, 0, 0]);
+
+        // but not inside a section
+        let e = VmdFile::probe(&mut Cursor::new(&saved[..30 + 20 + 4 + 111 + 50])).unwrap_err();
+        assert_eq!(e.section(), Some("bone keyframes"));
+        let e = VmdFile::probe(&mut Cursor::new(&saved[..saved.len() - 9 - 10])).unwrap_err();
+        assert_eq!(e.section(), Some("ik keyframes"));
+    }
+}
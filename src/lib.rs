@@ -13,17 +13,21 @@ extern crate enumflags_derive;
 #[macro_use]
 extern crate pod_io;
 
+#[cfg(feature = "parallel")]
+extern crate rayon;
+
 /*
 #[macro_use]
 extern crate nom;
 */
 
-pub mod pmd;
 //pub mod pmx;
-pub mod vmd;
 
 pub mod io;
 
+mod error;
+pub use error::{Error, ErrorKind, Result, Warning, WarningKind};
+
 //mod types;
 //mod traits;
 